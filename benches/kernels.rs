@@ -0,0 +1,251 @@
+//! Benchmarks of representative guest kernels.
+//!
+//! For every kernel, `interpreter/<kernel>` and `jit/<kernel>` measure how fast it runs
+//! interpreted and with all of its functions compiled, reported as bytecode instructions per
+//! second as counted by the interpreter. `speculate/<kernel>` is like `jit/<kernel>`, but compiles
+//! after profiling `Feedback::MIN_COUNT` interpreted runs. `compile/<kernel>` measures how long
+//! compiling all of them takes. Run with `cargo bench`, optionally filtered like
+//! `cargo bench -- jit/`.
+
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use jit_testing::{
+    debugger::DebugHook,
+    feedback::Feedback,
+    opcodes::{
+        __add, __call, __div, __idiv, __irem, __jumpnz, __jumpz, __load, __memload, __memstore,
+        __move, __rem, __return, __sub,
+    },
+    runtime::{Context, Func, Runner},
+};
+
+struct Kernel {
+    name: &'static str,
+    /// Functions called from an interpreted driver, starting with the first.
+    funcs: Vec<Vec<u16>>,
+    /// Initial registers.
+    regs: [i64; 8],
+}
+
+fn kernels() -> Vec<Kernel> {
+    vec![
+        Kernel {
+            name: "fibonacci",
+            // r1 = fib(r0) by naive recursion, spilling to a stack in mem at r4.
+            funcs: vec![vec![
+                __move(1, 0),
+                __jumpz(0, 22),
+                __load(2, 1),
+                __move(3, 0),
+                __sub(3, 2),
+                __jumpz(3, 18),
+                __memstore(4, 0),
+                __load(2, 8),
+                __add(4, 2),
+                __move(0, 3),
+                __call(0),
+                __load(2, 8),
+                __sub(4, 2),
+                __memload(0, 4),
+                __memstore(4, 1),
+                __add(4, 2),
+                __load(2, 2),
+                __sub(0, 2),
+                __call(0),
+                __load(2, 8),
+                __sub(4, 2),
+                __memload(3, 4),
+                __add(1, 3),
+                __return(),
+            ]],
+            regs: [20, 0, 0, 0, 0, 0, 0, 0],
+        },
+        Kernel {
+            name: "loop",
+            // Sums r1 down to 1.
+            funcs: vec![vec![
+                __load(2, 1),
+                __add(0, 1),
+                __sub(1, 2),
+                __jumpnz(1, -2),
+                __return(),
+            ]],
+            regs: [0, 100_000, 0, 0, 0, 0, 0, 0],
+        },
+        Kernel {
+            name: "memcpy",
+            // Copies r2 64-bit words from r0 to r1.
+            funcs: vec![vec![
+                __load(3, 8),
+                __load(4, 1),
+                __memload(5, 0),
+                __memstore(1, 5),
+                __add(0, 3),
+                __add(1, 3),
+                __sub(2, 4),
+                __jumpnz(2, -5),
+                __return(),
+            ]],
+            regs: [0, 0x8000, 0x1000, 0, 0, 0, 0, 0],
+        },
+        Kernel {
+            name: "checked_loop",
+            // Like `loop`, but checks r1 for zero at the head of every iteration, which never
+            // happens.
+            funcs: vec![vec![
+                __load(2, 1),
+                __jumpz(1, 4),
+                __add(0, 1),
+                __sub(1, 2),
+                __jumpnz(1, -3),
+                __return(),
+            ]],
+            regs: [0, 100_000, 0, 0, 0, 0, 0, 0],
+        },
+        Kernel {
+            name: "division",
+            // Divides r1 down to 1 by the divisor in r7 with DIV and IDIV, and with REM and IREM
+            // in a called helper. The divisor is not a constant, so nothing is strength reduced.
+            funcs: division(),
+            regs: [0, 20_000, 0, 0, 0, 0, 0, 7],
+        },
+        Kernel {
+            name: "division_by_8",
+            // Like `division`, but the divisor is a power of two, so speculation turns the
+            // unsigned divisions into shifts and masks.
+            funcs: division(),
+            regs: [0, 20_000, 0, 0, 0, 0, 0, 8],
+        },
+    ]
+}
+
+/// Functions of the `division` kernels, dividing by r7.
+fn division() -> Vec<Vec<u16>> {
+    vec![
+        vec![
+            __load(2, 1),
+            __move(3, 1),
+            __div(3, 7),
+            __move(4, 1),
+            __idiv(4, 7),
+            __call(1),
+            __add(0, 3),
+            __add(0, 4),
+            __add(0, 5),
+            __sub(1, 2),
+            __jumpnz(1, -9),
+            __return(),
+        ],
+        vec![
+            __move(5, 1),
+            __rem(5, 7),
+            __move(6, 1),
+            __irem(6, 7),
+            __return(),
+        ],
+    ]
+}
+
+fn context(kernel: &Kernel) -> Context {
+    let mut ctx = Context::default();
+    for code in &kernel.funcs {
+        ctx.funcs.push(Func::new(code.clone()));
+    }
+    ctx.funcs.push(Func::new(vec![__call(0), __return()]));
+    ctx
+}
+
+fn compile(ctx: &mut Context, kernel: &Kernel) {
+    for index in 0..kernel.funcs.len() {
+        ctx.compile(index).unwrap();
+    }
+}
+
+/// Compiles all of the kernel's functions with the feedback of enough interpreted runs to
+/// speculate on.
+fn speculate(ctx: &mut Context, kernel: &Kernel) {
+    ctx.feedback = Some(Feedback::default());
+    for _ in 0..Feedback::MIN_COUNT {
+        run(ctx, kernel);
+    }
+    compile(ctx, kernel);
+    // Collecting feedback would keep the driver off the interpreter's fast path.
+    ctx.feedback = None;
+}
+
+/// Runs the kernel from its initial registers through the interpreted driver.
+fn run(ctx: &mut Context, kernel: &Kernel) {
+    for (reg, value) in ctx.regs.iter_mut().zip(kernel.regs) {
+        reg.int = value;
+    }
+    ctx.pc = ctx.funcs.last().unwrap().code.as_ptr();
+    Runner::default().run(ctx);
+}
+
+/// Counts the instructions the interpreter executes.
+struct Counter(Rc<Cell<u64>>);
+
+impl DebugHook for Counter {
+    fn before_step(&mut self, _ctx: &mut Context) -> bool {
+        self.0.set(self.0.get() + 1);
+        true
+    }
+}
+
+fn count_insns(kernel: &Kernel) -> u64 {
+    let count = Rc::new(Cell::new(0));
+    let mut ctx = context(kernel);
+    ctx.debugger = Some(Box::new(Counter(count.clone())));
+    run(&mut ctx, kernel);
+    count.get()
+}
+
+fn bench_kernels(c: &mut Criterion) {
+    for kernel in kernels() {
+        let insns = count_insns(&kernel);
+
+        let mut group = c.benchmark_group("interpreter");
+        group.throughput(Throughput::Elements(insns));
+        let mut ctx = context(&kernel);
+        group.bench_function(kernel.name, |b| b.iter(|| run(&mut ctx, &kernel)));
+        group.finish();
+
+        let mut group = c.benchmark_group("compile");
+        group.bench_function(kernel.name, |b| {
+            b.iter_batched(
+                || context(&kernel),
+                |mut ctx| {
+                    compile(&mut ctx, &kernel);
+                    ctx
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.finish();
+
+        let mut group = c.benchmark_group("jit");
+        group.throughput(Throughput::Elements(insns));
+        let mut ctx = context(&kernel);
+        compile(&mut ctx, &kernel);
+        group.bench_function(kernel.name, |b| b.iter(|| run(&mut ctx, &kernel)));
+        group.finish();
+
+        let mut group = c.benchmark_group("speculate");
+        group.throughput(Throughput::Elements(insns));
+        let mut ctx = context(&kernel);
+        speculate(&mut ctx, &kernel);
+        group.bench_function(kernel.name, |b| b.iter(|| run(&mut ctx, &kernel)));
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(20)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(2));
+    targets = bench_kernels
+}
+criterion_main!(benches);
@@ -1,22 +1,28 @@
-use crate::{runtime::print_num, Context, Runner};
+use crate::runtime::{print_float, print_num, trace_block, Context, Runner};
 use std::arch::global_asm;
 
 #[cfg(all(target_arch = "x86_64", target_family = "unix"))]
 global_asm! {
     include_str!("asm/x64/system_v.asm"),
-    print_num=sym print_num
+    print_num=sym print_num,
+    print_float=sym print_float,
+    trace_block=sym trace_block
 }
 
 #[cfg(all(target_arch = "x86_64", target_family = "windows"))]
 global_asm! {
     include_str!("asm/x64/windows.asm"),
-    print_num=sym print_num
+    print_num=sym print_num,
+    print_float=sym print_float,
+    trace_block=sym trace_block
 }
 
 #[cfg(all(target_arch = "aarch64", target_family = "unix"))]
 global_asm! {
     include_str!("asm/a64/aapcs64.asm"),
-    print_num=sym print_num
+    print_num=sym print_num,
+    print_float=sym print_float,
+    trace_block=sym trace_block
 }
 
 #[allow(improper_ctypes)]
@@ -36,6 +42,15 @@ extern "C" {
     #[link_name = "asm_print"]
     pub(crate) fn print(runner: *mut Runner, ctx: *mut Context, num: i64);
 
+    #[link_name = "asm_printf"]
+    pub(crate) fn printf(runner: *mut Runner, ctx: *mut Context, bits: u64);
+
+    #[link_name = "asm_trace"]
+    pub(crate) fn trace(runner: *mut Runner, ctx: *mut Context, pc: *const u16);
+
     #[link_name = "asm_halt"]
     pub(crate) fn halt(runner: *mut Runner, ctx: *mut Context);
+
+    #[link_name = "asm_enter_virtual"]
+    pub(crate) fn enter_virtual(runner: *mut Runner, ctx: *mut Context);
 }
@@ -3,7 +3,10 @@
 .global asm_call_virtual_native
 .global asm_return_native_virtual
 .global asm_print
+.global asm_printf
+.global asm_trace
 .global asm_halt
+.global asm_enter_virtual
 
 asm_snapshot: // (x0: *Runner) aapcs64
     // Registers
@@ -66,6 +69,7 @@ asm_print: // (x20: *Runner, x19: *Context, x0: i64) custom
     // Save mapped registers
     // Save state
     stp x29, x30, [sp, -0x10]!
+    mov x1, x19
     // Call
     bl {print_num}
     // Restore state
@@ -73,6 +77,30 @@ asm_print: // (x20: *Runner, x19: *Context, x0: i64) custom
     // Restore mapped registers
     ret
 
+asm_printf: // (x20: *Runner, x19: *Context, x0: u64) custom
+    // Save mapped registers
+    // Save state
+    stp x29, x30, [sp, -0x10]!
+    mov x1, x19
+    // Call
+    bl {print_float}
+    // Restore state
+    ldp x29, x30, [sp], 0x10
+    // Restore mapped registers
+    ret
+
+asm_trace: // (x20: *Runner, x19: *Context, x0: *const u16) custom
+    // Save mapped registers
+    // Save state
+    stp x29, x30, [sp, -0x10]!
+    mov x1, x0
+    mov x0, x19
+    // Call
+    bl {trace_block}
+    // Restore state
+    ldp x29, x30, [sp], 0x10
+    ret
+
 asm_halt: // (x20: *Runner, x19: *Context) custom
     str xzr, [x20, 0x60] // running
     // Save mapped registers
@@ -94,3 +122,24 @@ asm_halt: // (x20: *Runner, x19: *Context) custom
     ldp x1, x2, [x0, 0x80]
     stp x1, x2, [sp, 0x10]
     ret
+
+asm_enter_virtual: // (x20: *Runner, x19: *Context) custom
+    // Save mapped registers
+    str x21, [x19, 0x58] // callstack
+    // Restore snapshot
+    mov x0, x20
+    ldr x18, [x0]
+    ldp x19, x20, [x0, 0x8]
+    ldp x21, x22, [x0, 0x18]
+    ldp x23, x24, [x0, 0x28]
+    ldp x25, x26, [x0, 0x38]
+    ldp x27, x28, [x0, 0x48]
+    ldp lr, fp, [x0, 0x58]
+    ldr x1, [x0, 0x68]
+    mov sp, x1
+    // Stack top
+    ldp x1, x2, [x0, 0x70]
+    stp x1, x2, [sp]
+    ldp x1, x2, [x0, 0x80]
+    stp x1, x2, [sp, 0x10]
+    ret
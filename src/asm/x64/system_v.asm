@@ -3,7 +3,10 @@
 .global asm_call_virtual_native
 .global asm_return_native_virtual
 .global asm_print
+.global asm_printf
+.global asm_trace
 .global asm_halt
+.global asm_enter_virtual
 
 asm_snapshot: // (rdi: *Runner) system_v
     // Registers
@@ -70,6 +73,45 @@ asm_print: // (rdi: *Runner, rsi: *Context, rax: i64) custom
     mov rsp, [rsi + 88] // callstack
     ret
 
+asm_printf: // (rdi: *Runner, rsi: *Context, rax: u64) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Save state
+    mov rsp, [rdi + 8] // stack snapshot
+    sub rsp, 24
+    mov [rsp], rdi
+    mov [rsp + 8], rsi
+    mov rdi, rax
+    // Call
+    call {print_float}
+    // Restore state
+    mov rsi, [rsp + 8]
+    mov rdi, [rsp]
+    add rsp, 24
+    // Restore mapped registers
+    mov rsp, [rsi + 88] // callstack
+    ret
+
+asm_trace: // (rdi: *Runner, rsi: *Context, rax: *const u16) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Save state
+    mov rsp, [rdi + 8] // stack snapshot
+    sub rsp, 24
+    mov [rsp], rdi
+    mov [rsp + 8], rsi
+    mov rdi, rsi
+    mov rsi, rax
+    // Call
+    call {trace_block}
+    // Restore state
+    mov rsi, [rsp + 8]
+    mov rdi, [rsp]
+    add rsp, 24
+    // Restore mapped registers
+    mov rsp, [rsi + 88] // callstack
+    ret
+
 asm_halt: // (rdi: *Runner, rsi: *Context) custom
     mov qword ptr [rdi + 96], 0 // running
     // Save mapped registers
@@ -88,3 +130,19 @@ asm_halt: // (rdi: *Runner, rsi: *Context) custom
     movups [rsp + 16], xmm0
     ret
 
+asm_enter_virtual: // (rdi: *Runner, rsi: *Context) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Restore snapshot
+    mov rbx, [rdi]
+    mov rsp, [rdi + 8]
+    mov rbp, [rdi + 16]
+    mov r12, [rdi + 24]
+    mov r13, [rdi + 32]
+    mov r14, [rdi + 40]
+    mov r15, [rdi + 48]
+    movups xmm0, [rdi + 56]
+    movups [rsp], xmm0
+    movups xmm0, [rdi + 72]
+    movups [rsp + 16], xmm0
+    ret
@@ -3,7 +3,10 @@
 .global asm_call_virtual_native
 .global asm_return_native_virtual
 .global asm_print
+.global asm_printf
+.global asm_trace
 .global asm_halt
+.global asm_enter_virtual
 
 asm_snapshot: // (rcx: *Runner) windows
     mov [rcx], rbx
@@ -65,6 +68,7 @@ asm_print: // (rdi: *Runner, rsi: *Context, rax: i64) custom
     mov rsp, [rdi + 8] // stack snapshot
     sub rsp, 8
     mov rcx, rax
+    mov rdx, rsi
     // Call
     call {print_num}
     // Restore State
@@ -73,6 +77,38 @@ asm_print: // (rdi: *Runner, rsi: *Context, rax: i64) custom
     mov rsp, [rsi + 88] // callstack
     ret
 
+asm_printf: // (rdi: *Runner, rsi: *Context, rax: u64) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Save state
+    mov rsp, [rdi + 8] // stack snapshot
+    sub rsp, 8
+    mov rcx, rax
+    mov rdx, rsi
+    // Call
+    call {print_float}
+    // Restore State
+    add rsp, 8
+    // Restore mapped registers
+    mov rsp, [rsi + 88] // callstack
+    ret
+
+asm_trace: // (rdi: *Runner, rsi: *Context, rax: *const u16) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Save state
+    mov rsp, [rdi + 8] // stack snapshot
+    sub rsp, 8
+    mov rcx, rsi
+    mov rdx, rax
+    // Call
+    call {trace_block}
+    // Restore State
+    add rsp, 8
+    // Restore mapped registers
+    mov rsp, [rsi + 88] // callstack
+    ret
+
 asm_halt: // (rdi: *Runner, rsi: *Context) custom
     mov qword ptr [rdi + 112], 0 // running
     // Save mapped registers
@@ -94,3 +130,22 @@ asm_halt: // (rdi: *Runner, rsi: *Context) custom
     movups [rsp + 16], xmm0
     ret
 
+asm_enter_virtual: // (rdi: *Runner, rsi: *Context) custom
+    // Save mapped registers
+    mov [rsi + 88], rsp // callstack
+    // Restore snapshot
+    mov rcx, rdi
+    mov rbx, [rcx]
+    mov rsp, [rcx + 8]
+    mov rbp, [rcx + 16]
+    mov rsi, [rcx + 24]
+    mov rdi, [rcx + 32]
+    mov r12, [rcx + 40]
+    mov r13, [rcx + 48]
+    mov r14, [rcx + 56]
+    mov r15, [rcx + 64]
+    movups xmm0, [rdi + 72]
+    movups [rsp], xmm0
+    movups xmm0, [rdi + 88]
+    movups [rsp + 16], xmm0
+    ret
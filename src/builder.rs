@@ -0,0 +1,268 @@
+use anyhow::anyhow;
+
+use crate::opcodes::{__ljump, __switch, BRANCH, JUMP, JUMPNZ, JUMPZ, LJUMP};
+
+/// A position in the code of a [`Builder`], bound with [`Builder::bind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Clone, Copy, Debug)]
+enum Item {
+    Word(u16),
+    /// The first word of a jump or branch, to be pointed at the label.
+    Jump(u16, Label),
+    /// An entry of the table of the `SWITCH` item at the index.
+    Case(usize, Label),
+}
+
+/// Assembles a function's bytecode with jumps to labels.
+///
+/// Jumps start out in their short form. [`Builder::finish`] relaxes those whose label is out of
+/// range: a `JUMP` becomes an `LJUMP`, and a conditional jump the inverted condition skipping
+/// over an `LJUMP`. Relaxing moves later code, so it repeats until every jump fits.
+/// `SWITCH` tables cannot be relaxed; their labels have to be within a 16-bit offset.
+#[derive(Debug, Default)]
+pub struct Builder {
+    items: Vec<Item>,
+    /// Index of the item each label is bound to.
+    labels: Vec<Option<usize>>,
+}
+
+impl Builder {
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds `label` to the next instruction.
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "{label:?} is already bound");
+        self.labels[label.0] = Some(self.items.len());
+    }
+
+    pub fn push(&mut self, word: u16) {
+        self.items.push(Item::Word(word));
+    }
+
+    /// Appends a jump to `label`: `insn` is a `JUMP`, `JUMPZ` or `JUMPNZ` like `__jumpz(r, 0)`,
+    /// or the first word of an `LJUMP` or a branch like `__beq(a, b, 0)[0]`.
+    pub fn jump(&mut self, insn: u16, label: Label) {
+        assert!(offset_bits(insn).is_some(), "0x{insn:04x} is not a jump");
+        self.items.push(Item::Jump(insn, label));
+    }
+
+    /// Appends a `SWITCH` on `reg` whose table jumps to `labels`.
+    pub fn switch(&mut self, reg: u16, labels: &[Label]) {
+        assert!(labels.len() < 0x200, "Too many cases: {}", labels.len());
+        let at = self.items.len();
+        self.push(__switch(reg, labels.len() as u16));
+        self.items
+            .extend(labels.iter().map(|&label| Item::Case(at, label)));
+    }
+
+    /// Resolves the labels and returns the bytecode.
+    pub fn finish(self) -> anyhow::Result<Vec<u16>> {
+        let targets = self
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(label, item)| item.ok_or_else(|| anyhow!("Unbound label {label}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut long = vec![false; self.items.len()];
+        let starts = loop {
+            let mut starts = Vec::with_capacity(self.items.len() + 1);
+            let mut pc = 0;
+            for (item, long) in self.items.iter().zip(&long) {
+                starts.push(pc);
+                pc += match *item {
+                    Item::Word(_) | Item::Case(..) => 1,
+                    Item::Jump(insn, _) => size(insn, *long),
+                };
+            }
+            starts.push(pc);
+            let mut changed = false;
+            for (at, item) in self.items.iter().enumerate() {
+                let Item::Jump(insn, label) = *item else {
+                    continue;
+                };
+                let offset = starts[targets[label.0]] as i64 - starts[at] as i64;
+                if !long[at] && insn & 0xf000 != LJUMP && encode(insn, offset).is_none() {
+                    long[at] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break starts;
+            }
+        };
+        let mut code = Vec::with_capacity(starts[self.items.len()]);
+        for (at, item) in self.items.iter().enumerate() {
+            let (insn, label) = match *item {
+                Item::Word(word) => {
+                    code.push(word);
+                    continue;
+                }
+                Item::Case(switch, label) => {
+                    let offset = starts[targets[label.0]] as i64 - starts[switch] as i64;
+                    let offset = i16::try_from(offset)
+                        .map_err(|_| anyhow!("Case label {} out of range", label.0))?;
+                    code.push(offset as u16);
+                    continue;
+                }
+                Item::Jump(insn, label) => (insn, label),
+            };
+            let mut offset = starts[targets[label.0]] as i64 - starts[at] as i64;
+            if !long[at] && insn & 0xf000 != LJUMP {
+                code.extend(&encode(insn, offset).unwrap()[..size(insn, false)]);
+                continue;
+            }
+            if long[at] && insn & 0xf000 != JUMP {
+                // The inverted condition skips the `LJUMP` behind it.
+                let inverted = invert(insn);
+                let skip = size(inverted, false);
+                code.extend(&encode(inverted, skip as i64 + 2).unwrap()[..skip]);
+                offset -= skip as i64;
+            }
+            let words = encode(LJUMP, offset)
+                .ok_or_else(|| anyhow!("Jump to label {} out of range", label.0))?;
+            code.extend(words);
+        }
+        Ok(code)
+    }
+}
+
+impl Extend<u16> for Builder {
+    fn extend<T: IntoIterator<Item = u16>>(&mut self, words: T) {
+        self.items.extend(words.into_iter().map(Item::Word));
+    }
+}
+
+/// Returns the number of words of the jump `insn`, relaxed if `long` is set.
+fn size(insn: u16, long: bool) -> usize {
+    match (insn & 0xf000, long) {
+        (LJUMP, _) | (JUMP, true) | (BRANCH, false) => 2,
+        (BRANCH, true) => 4,
+        (_, true) => 3,
+        (_, false) => 1,
+    }
+}
+
+/// Returns the width of the offset of a jump instruction.
+fn offset_bits(insn: u16) -> Option<u32> {
+    match insn & 0xf000 {
+        JUMP => Some(12),
+        LJUMP => Some(28),
+        JUMPZ | JUMPNZ => Some(9),
+        BRANCH => Some(16),
+        _ => None,
+    }
+}
+
+/// Returns the short form of the jump `insn` with `offset`, if it fits, padded to two words.
+fn encode(insn: u16, offset: i64) -> Option<[u16; 2]> {
+    let bits = offset_bits(insn)?;
+    let max = (1 << (bits - 1)) - 1;
+    if !(-max - 1..=max).contains(&offset) {
+        return None;
+    }
+    Some(match insn & 0xf000 {
+        JUMP => [insn | offset as u16 & 0xfff, 0],
+        LJUMP => __ljump(offset as i32),
+        BRANCH => [insn, offset as u16],
+        _ => [insn | (offset as u16 & 0x1ff) << 3, 0],
+    })
+}
+
+/// Returns the conditional jump taken exactly when `insn` is not.
+fn invert(insn: u16) -> u16 {
+    match insn & 0xf000 {
+        JUMPZ => insn & !JUMPZ | JUMPNZ,
+        JUMPNZ => insn & !JUMPNZ | JUMPZ,
+        _ => insn ^ 0x0040,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Builder;
+    use crate::opcodes::{
+        __beq, __bge, __blt, __jump, __jumpnz, __jumpz, __ljump, __noop, __return, __switch,
+    };
+
+    #[test]
+    fn test_short_jumps() {
+        let mut builder = Builder::default();
+        let (start, end) = (builder.label(), builder.label());
+        builder.bind(start);
+        builder.jump(__beq(1, 2, 0)[0], end);
+        builder.push(__noop());
+        builder.jump(__jump(0), start);
+        builder.bind(end);
+        builder.push(__return());
+        let mut expected = __beq(1, 2, 4).to_vec();
+        expected.extend([__noop(), __jump(-3), __return()]);
+        assert_eq!(builder.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_relaxation_moves_labels() {
+        // Relaxing the second jump moves the first one's label out of range.
+        let mut builder = Builder::default();
+        let (near, far) = (builder.label(), builder.label());
+        builder.jump(__jumpz(1, 0), near);
+        builder.jump(__jumpnz(3, 0), far);
+        builder.extend([__noop(); 253]);
+        builder.bind(near);
+        builder.extend([__noop(); 5]);
+        builder.bind(far);
+        builder.push(__return());
+        let mut expected = vec![__jumpnz(1, 3)];
+        expected.extend(__ljump(258));
+        expected.push(__jumpz(3, 3));
+        expected.extend(__ljump(260));
+        expected.extend([__noop(); 258]);
+        expected.push(__return());
+        assert_eq!(builder.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_relaxed_branch() {
+        let mut builder = Builder::default();
+        let far = builder.label();
+        builder.jump(__blt(1, 2, 0)[0], far);
+        builder.extend(vec![__noop(); 40000]);
+        builder.bind(far);
+        builder.push(__return());
+        let mut expected = __bge(1, 2, 4).to_vec();
+        expected.extend(__ljump(40002));
+        expected.extend(vec![__noop(); 40000]);
+        expected.push(__return());
+        assert_eq!(builder.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_switch_table() {
+        // The relaxed jump in front of the second case moves it, but not the `SWITCH`.
+        let mut builder = Builder::default();
+        let (first, second) = (builder.label(), builder.label());
+        builder.switch(3, &[second, first, second]);
+        builder.bind(first);
+        builder.jump(__jumpz(1, 0), second);
+        builder.extend([__noop(); 300]);
+        builder.bind(second);
+        builder.push(__return());
+        let mut expected = vec![__switch(3, 3), 307, 4, 307, __jumpnz(1, 3)];
+        expected.extend(__ljump(302));
+        expected.extend([__noop(); 300]);
+        expected.push(__return());
+        assert_eq!(builder.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_unbound_label() {
+        let mut builder = Builder::default();
+        let label = builder.label();
+        builder.jump(__jump(0), label);
+        assert!(builder.finish().is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{cell::Cell, rc::Rc};
+
+use dynasmrt::ExecutableBuffer;
+
+use crate::{debuginfo::Registration, ir::Frame};
+
+/// Compiled code of one function.
+pub struct Code {
+    /// Index of the function in `Context::funcs`.
+    pub func: usize,
+    pub buf: ExecutableBuffer,
+    /// Offset of every native return address in `buf` together with the bytecode frames it
+    /// resumes, innermost first.
+    pub returns: Vec<(usize, Vec<Frame>)>,
+    /// Offset of the native code of every instruction in `buf` and its bytecode location.
+    pub lines: Vec<(usize, Frame)>,
+    /// Keeps the code registered with gdb while it is alive.
+    pub debug: Option<Registration>,
+    /// Keeps the counter the code increments alive, see `CompileOptions::counter`.
+    pub counter: Option<Rc<Cell<u64>>>,
+}
+
+impl Code {
+    /// Returns the bytecode frames the native return address `addr` resumes, if it points
+    /// into this code.
+    pub fn frames(&self, addr: *const ()) -> Option<&[Frame]> {
+        let offset = (addr as usize).checked_sub(self.buf.as_ptr() as usize)?;
+        self.returns
+            .iter()
+            .find(|(ret, _)| *ret == offset)
+            .map(|(_, frames)| &frames[..])
+    }
+
+    fn contains(&self, addr: *const ()) -> bool {
+        self.buf.as_ptr_range().contains(&(addr as *const u8))
+    }
+}
+
+/// Owns the executable memory of compiled functions.
+///
+/// Recompiling a function retires its previous code instead of freeing it, since native frames
+/// on the callstack may still return into it. Retired code is freed by [`CodeCache::collect`]
+/// once no return address points into it anymore.
+#[derive(Default)]
+pub struct CodeCache {
+    /// Current code of each function, indexed like `Context::funcs`.
+    code: Vec<Option<Code>>,
+    retired: Vec<Code>,
+}
+
+impl CodeCache {
+    /// Returns the current code of `func`, if it is compiled.
+    pub fn get(&self, func: usize) -> Option<&Code> {
+        self.code.get(func)?.as_ref()
+    }
+
+    /// Returns the current or retired code containing `addr`.
+    pub fn lookup(&self, addr: *const ()) -> Option<&Code> {
+        self.code
+            .iter()
+            .flatten()
+            .chain(&self.retired)
+            .find(|code| code.contains(addr))
+    }
+
+    /// Makes `code` the code of its function and retires the code it replaces.
+    ///
+    /// `redirects` lists the offset and new target of every call site in the replaced code, so
+    /// frames still running in it no longer call code that may be freed.
+    pub fn insert(&mut self, code: Code, redirects: &[(usize, usize)]) -> anyhow::Result<()> {
+        let func = code.func;
+        if self.code.len() <= func {
+            self.code.resize_with(func + 1, || None);
+        }
+        let old = self.code[func].replace(code);
+        self.retire(old, redirects)
+    }
+
+    /// Drops the current code of `func` and retires it like [`CodeCache::insert`].
+    pub fn remove(&mut self, func: usize, redirects: &[(usize, usize)]) -> anyhow::Result<()> {
+        let old = self.code.get_mut(func).and_then(Option::take);
+        self.retire(old, redirects)
+    }
+
+    fn retire(&mut self, old: Option<Code>, redirects: &[(usize, usize)]) -> anyhow::Result<()> {
+        let Some(mut old) = old else {
+            return Ok(());
+        };
+        for &(offset, target) in redirects {
+            patch(&mut old.buf, offset, target)?;
+        }
+        self.retired.push(old);
+        Ok(())
+    }
+
+    /// Overwrites the 64-bit call target at `offset` in the current code of `func`.
+    pub fn patch(&mut self, func: usize, offset: usize, target: usize) -> anyhow::Result<()> {
+        let code = self.code[func].as_mut().expect("function is compiled");
+        patch(&mut code.buf, offset, target)
+    }
+
+    /// Frees retired code that none of `return_addrs` points into.
+    pub fn collect(&mut self, return_addrs: &[*const ()]) {
+        self.retired
+            .retain(|code| return_addrs.iter().any(|&addr| code.contains(addr)));
+    }
+
+    /// Returns the number of retired buffers that are still alive.
+    pub fn retired(&self) -> usize {
+        self.retired.len()
+    }
+}
+
+fn patch(buf: &mut ExecutableBuffer, offset: usize, target: usize) -> anyhow::Result<()> {
+    let mut code = std::mem::take(buf).make_mut()?;
+    code[offset..offset + 8].copy_from_slice(&target.to_ne_bytes());
+    *buf = code.make_exec()?;
+    Ok(())
+}
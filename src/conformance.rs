@@ -0,0 +1,472 @@
+//! Data-driven conformance tests run against every engine.
+//!
+//! Each file in `conformance/` covers one opcode family and consists of test cases like this:
+//!
+//! ```text
+//! # Comments run to the end of the line.
+//! test countdown
+//! regs r1=3 r2=1.5     # initial registers, zero if not given; floats are stored as bits
+//! mem 0x10=-1          # initial 64-bit values in mem, zero if not given
+//! func                 # function 0, called first
+//!     LOAD r2, 1
+//! loop:
+//!     SUB r1, r2
+//!     JUMPNZ r1, loop  # a label or an offset relative to this instruction
+//!     CALL 1
+//!     RETURN
+//! func                 # function 1
+//!     .word 0x0400     # a raw instruction
+//! expect regs r1=0 r2=1
+//! expect mem 0x10=-1
+//! expect output 1 2.0  # printed numbers, none if not given
+//! expect trap none     # `InvalidInstruction(0xf000)` and so on, none if not given
+//! expect rejected      # the JIT rejects a function, which stays interpreted
+//! ```
+//!
+//! Every case runs in the interpreter, both on its fast path and one instruction at a time, with
+//! all functions compiled and with all functions compiled speculatively on feedback from
+//! interpreted runs. Besides matching the expectations, the other runs have to leave exactly
+//! the same registers, memory, output and trap as the fast interpreter.
+//!
+//! Only the JIT for the host architecture is built, so the aarch64 back end is covered by
+//! running the suite under qemu-user:
+//!
+//! ```text
+//! CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER=aarch64-linux-gnu-gcc \
+//! CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER="qemu-aarch64 -L /usr/aarch64-linux-gnu" \
+//!     cargo test --target aarch64-unknown-linux-gnu conformance
+//! ```
+
+use std::collections::HashMap;
+
+use crate::{
+    builder::{Builder, Label},
+    harness::{run, Tier, MEM_SIZE},
+    opcodes::{
+        __add, __addo, __beq, __bge, __bgeu, __blt, __bltu, __bne, __call, __div, __divrem, __fadd,
+        __fcmp, __fdiv, __fmul, __fsub, __ftoi, __halt, __iaddo, __idiv, __idivrem, __iload,
+        __imul, __imulo, __irem, __isubo, __itof, __jump, __jumpnz, __jumpz, __ljump, __load,
+        __memload, __memstore, __move, __mul, __mulh, __mulhu, __mulo, __noop, __print, __printf,
+        __rem, __return, __sub, __subo, __switch, LJUMP,
+    },
+    runtime::Trap,
+};
+
+#[derive(Default)]
+struct Case {
+    name: String,
+    /// Line of the `test` directive.
+    line: usize,
+    regs: [i64; 8],
+    mem: Vec<(u16, i64)>,
+    funcs: Vec<Vec<u16>>,
+    expect_regs: Vec<(usize, i64)>,
+    expect_mem: Vec<(u16, i64)>,
+    expect_output: Vec<String>,
+    expect_trap: Option<Trap>,
+    /// The JIT rejects at least one function. Otherwise compiling has to succeed.
+    expect_rejected: bool,
+}
+
+/// Instructions and labels of the function being parsed, assembled once it is complete.
+#[derive(Default)]
+struct Source<'a> {
+    insns: Vec<(usize, &'a str)>,
+    labels: HashMap<&'a str, usize>,
+}
+
+fn parse(family: &str, text: &str) -> Vec<Case> {
+    let mut cases: Vec<Case> = Vec::new();
+    let mut source: Option<Source> = None;
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let fail = |message: &str| -> ! { panic!("{family}.test:{line_number}: {message}") };
+        let line = line.split('#').next().unwrap().trim();
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some(&directive) = words.first() else {
+            continue;
+        };
+        if directive == "test" {
+            if let Some(source) = source.take() {
+                finish(family, cases.last_mut().unwrap(), source);
+            }
+            let [_, name] = words[..] else {
+                fail("expected `test <name>`");
+            };
+            cases.push(Case {
+                name: name.to_string(),
+                line: line_number,
+                ..Case::default()
+            });
+            continue;
+        }
+        let Some(case) = cases.last_mut() else {
+            fail("expected `test <name>` first");
+        };
+        match directive {
+            "func" => {
+                if let Some(source) = source.replace(Source::default()) {
+                    finish(family, case, source);
+                }
+            }
+            "regs" => {
+                for (reg, value) in assign_regs(&words[1..]).unwrap_or_else(|err| fail(&err)) {
+                    case.regs[reg] = value;
+                }
+            }
+            "mem" => case.mem = assign_mem(&words[1..]).unwrap_or_else(|err| fail(&err)),
+            "expect" => {
+                if let Some(source) = source.take() {
+                    finish(family, case, source);
+                }
+                let result = match words.get(1).copied() {
+                    Some("regs") => assign_regs(&words[2..]).map(|regs| case.expect_regs = regs),
+                    Some("mem") => assign_mem(&words[2..]).map(|mem| case.expect_mem = mem),
+                    Some("output") => words[2..]
+                        .iter()
+                        .map(|word| printed(word).ok_or(format!("invalid number `{word}`")))
+                        .collect::<Result<_, _>>()
+                        .map(|output| case.expect_output = output),
+                    Some("trap") => parse_trap(&words[2..]).map(|trap| case.expect_trap = trap),
+                    Some("rejected") if words.len() == 2 => {
+                        case.expect_rejected = true;
+                        Ok(())
+                    }
+                    _ => Err("expected `expect regs|mem|output|trap|rejected`".to_string()),
+                };
+                result.unwrap_or_else(|err| fail(&err));
+            }
+            _ => {
+                let Some(source) = &mut source else {
+                    fail("instruction outside of a function");
+                };
+                if let Some(label) = line.strip_suffix(':') {
+                    if source.labels.insert(label, source.insns.len()).is_some() {
+                        fail(&format!("duplicate label `{label}`"));
+                    }
+                } else {
+                    source.insns.push((line_number, line));
+                }
+            }
+        }
+    }
+    if let Some(source) = source {
+        finish(family, cases.last_mut().unwrap(), source);
+    }
+    cases
+}
+
+/// Assembles a function and appends it to the case.
+fn finish(family: &str, case: &mut Case, source: Source) {
+    let mut builder = Builder::default();
+    let labels = source
+        .labels
+        .keys()
+        .map(|&name| (name, builder.label()))
+        .collect::<HashMap<_, _>>();
+    for index in 0..=source.insns.len() {
+        for (name, _) in source.labels.iter().filter(|(_, at)| **at == index) {
+            builder.bind(labels[name]);
+        }
+        if let Some(&(line, insn)) = source.insns.get(index) {
+            assemble(insn, &labels, &mut builder)
+                .unwrap_or_else(|err| panic!("{family}.test:{line}: {err}"));
+        }
+    }
+    let code = builder
+        .finish()
+        .unwrap_or_else(|err| panic!("{family}.test:{}: {err}", case.line));
+    case.funcs.push(code);
+}
+
+/// Encodes an instruction like `ADD r0, r1` into `builder`.
+///
+/// Jumps to labels are relaxed by the builder if they are out of range, while offsets are
+/// encoded as given. `SWITCH r0, a, b` is followed by its table, with the cases either all
+/// labels or all offsets relative to the `SWITCH`.
+fn assemble(
+    insn: &str,
+    labels: &HashMap<&str, Label>,
+    builder: &mut Builder,
+) -> Result<(), String> {
+    let (mnemonic, operands) = insn.split_once(char::is_whitespace).unwrap_or((insn, ""));
+    let operands = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|operand| !operand.is_empty())
+        .collect::<Vec<_>>();
+    let reg = |operand: &str| register(operand).ok_or(format!("invalid register `{operand}`"));
+    let immediate = |operand: &str, min: i64, max: i64| match integer(operand) {
+        Some(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!(
+            "expected a number in {min}..={max}, got `{operand}`"
+        )),
+    };
+    let binary = match mnemonic {
+        "MOVE" => Some(__move as fn(u16, u16) -> u16),
+        "MEMLOAD" => Some(__memload as _),
+        "MEMSTORE" => Some(__memstore as _),
+        "ADD" => Some(__add as _),
+        "SUB" => Some(__sub as _),
+        "MUL" => Some(__mul as _),
+        "IMUL" => Some(__imul as _),
+        "DIV" => Some(__div as _),
+        "IDIV" => Some(__idiv as _),
+        "REM" => Some(__rem as _),
+        "IREM" => Some(__irem as _),
+        "FADD" => Some(__fadd as _),
+        "FSUB" => Some(__fsub as _),
+        "FMUL" => Some(__fmul as _),
+        "FDIV" => Some(__fdiv as _),
+        "FCMP" => Some(__fcmp as _),
+        "ITOF" => Some(__itof as _),
+        "FTOI" => Some(__ftoi as _),
+        "ADDO" => Some(__addo as _),
+        "IADDO" => Some(__iaddo as _),
+        "SUBO" => Some(__subo as _),
+        "ISUBO" => Some(__isubo as _),
+        "MULO" => Some(__mulo as _),
+        "IMULO" => Some(__imulo as _),
+        "MULH" => Some(__mulh as _),
+        "MULHU" => Some(__mulhu as _),
+        _ => None,
+    };
+    if let (Some(binary), &[dst, src]) = (binary, &operands[..]) {
+        builder.push(binary(reg(dst)?, reg(src)?));
+        return Ok(());
+    }
+    let offset = |operand: &str, bits: u32| {
+        let offset = immediate(operand, i64::MIN, i64::MAX)?;
+        let max = (1 << (bits - 1)) - 1;
+        if !(-max - 1..=max).contains(&offset) {
+            return Err(format!("jump to `{operand}` out of range"));
+        }
+        Ok(offset)
+    };
+    let jump = |builder: &mut Builder, encode: &dyn Fn(i16) -> u16, operand: &str, bits: u32| {
+        match labels.get(operand) {
+            Some(&label) => builder.jump(encode(0), label),
+            None => builder.push(encode(offset(operand, bits)? as i16)),
+        }
+        Ok(())
+    };
+    let branch = match mnemonic {
+        "BEQ" => Some(__beq as fn(u16, u16, i16) -> [u16; 2]),
+        "BNE" => Some(__bne as _),
+        "BLT" => Some(__blt as _),
+        "BGE" => Some(__bge as _),
+        "BLTU" => Some(__bltu as _),
+        "BGEU" => Some(__bgeu as _),
+        _ => None,
+    };
+    if let (Some(branch), &[a, b, target]) = (branch, &operands[..]) {
+        let (a, b) = (reg(a)?, reg(b)?);
+        match labels.get(target) {
+            Some(&label) => builder.jump(branch(a, b, 0)[0], label),
+            None => builder.extend(branch(a, b, offset(target, 16)? as i16)),
+        }
+        return Ok(());
+    }
+    let insn = match (mnemonic, &operands[..]) {
+        ("NOOP", []) => __noop(),
+        ("RETURN", []) => __return(),
+        ("HALT", []) => __halt(),
+        ("DIVREM", &[dst, rem, src]) => __divrem(reg(dst)?, reg(rem)?, reg(src)?),
+        ("IDIVREM", &[dst, rem, src]) => __idivrem(reg(dst)?, reg(rem)?, reg(src)?),
+        ("PRINT", &[src]) => __print(reg(src)?),
+        ("PRINTF", &[src]) => __printf(reg(src)?),
+        ("LOAD", &[dst, value]) => __load(reg(dst)?, immediate(value, 0, 0x1ff)? as u16),
+        ("ILOAD", &[dst, value]) => __iload(reg(dst)?, immediate(value, -0x100, 0xff)? as i16),
+        ("JUMP", &[target]) => return jump(builder, &__jump, target, 12),
+        ("LJUMP", &[target]) => {
+            match labels.get(target) {
+                Some(&label) => builder.jump(LJUMP, label),
+                None => builder.extend(__ljump(offset(target, 28)? as i32)),
+            }
+            return Ok(());
+        }
+        ("JUMPZ", &[cond, target]) => {
+            let cond = reg(cond)?;
+            return jump(builder, &|offset| __jumpz(cond, offset), target, 9);
+        }
+        ("JUMPNZ", &[cond, target]) => {
+            let cond = reg(cond)?;
+            return jump(builder, &|offset| __jumpnz(cond, offset), target, 9);
+        }
+        ("SWITCH", &[index, ref cases @ ..]) => {
+            let index = reg(index)?;
+            if cases.len() >= 0x200 {
+                return Err(format!("too many cases: {}", cases.len()));
+            }
+            if let Some(cases) = cases
+                .iter()
+                .map(|case| labels.get(case).copied())
+                .collect::<Option<Vec<_>>>()
+            {
+                builder.switch(index, &cases);
+                return Ok(());
+            }
+            builder.push(__switch(index, cases.len() as u16));
+            for case in cases {
+                builder.push(offset(case, 16)? as u16);
+            }
+            return Ok(());
+        }
+        ("CALL", &[index]) => __call(immediate(index, 0, 0xfff)? as u16),
+        (".word", &[value]) => immediate(value, 0, 0xffff)? as u16,
+        _ => return Err(format!("invalid instruction `{insn}`")),
+    };
+    builder.push(insn);
+    Ok(())
+}
+
+fn register(text: &str) -> Option<u16> {
+    text.strip_prefix('r')?.parse().ok().filter(|reg| *reg < 8)
+}
+
+/// Parses an integer like [`integer`], or else a float like `1.5`, `-inf` or `NaN` as its bits.
+fn number(text: &str) -> Option<i64> {
+    integer(text).or_else(|| Some(text.parse::<f64>().ok()?.to_bits() as i64))
+}
+
+/// Parses an integer or float as `Context::print` and `Context::print_float` print it.
+fn printed(text: &str) -> Option<String> {
+    match integer(text) {
+        Some(value) => Some(value.to_string()),
+        None => Some(format!("{:?}", text.parse::<f64>().ok()?)),
+    }
+}
+
+/// Parses decimal and `0x` hexadecimal integers. Hexadecimal integers may exceed `i64::MAX` and
+/// wrap around.
+fn integer(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn split_assignment(word: &str) -> Result<(&str, i64), String> {
+    let (key, value) = word
+        .split_once('=')
+        .ok_or(format!("expected `<key>=<value>`, got `{word}`"))?;
+    let value = number(value).ok_or(format!("invalid number `{value}`"))?;
+    Ok((key, value))
+}
+
+fn assign_regs(words: &[&str]) -> Result<Vec<(usize, i64)>, String> {
+    words
+        .iter()
+        .map(|word| {
+            let (reg, value) = split_assignment(word)?;
+            let reg = register(reg).ok_or(format!("invalid register `{reg}`"))?;
+            Ok((reg as usize, value))
+        })
+        .collect()
+}
+
+fn assign_mem(words: &[&str]) -> Result<Vec<(u16, i64)>, String> {
+    words
+        .iter()
+        .map(|word| {
+            let (addr, value) = split_assignment(word)?;
+            let addr = integer(addr)
+                .and_then(|addr| u16::try_from(addr).ok())
+                .ok_or(format!("invalid address `{addr}`"))?;
+            Ok((addr, value))
+        })
+        .collect()
+}
+
+fn parse_trap(words: &[&str]) -> Result<Option<Trap>, String> {
+    let [trap] = words else {
+        return Err("expected a single trap".to_string());
+    };
+    if *trap == "none" {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid trap `{trap}`");
+    let (kind, insn) = trap
+        .strip_suffix(')')
+        .and_then(|trap| trap.split_once('('))
+        .ok_or_else(invalid)?;
+    let insn = integer(insn)
+        .and_then(|insn| u16::try_from(insn).ok())
+        .ok_or_else(invalid)?;
+    Ok(Some(match kind {
+        "InvalidInstruction" => Trap::InvalidInstruction(insn),
+        "InvalidFunction" => Trap::InvalidFunction(insn),
+        "CallstackOverflow" => Trap::CallstackOverflow(insn),
+        "Overflow" => Trap::Overflow(insn),
+        "DivisionByZero" => Trap::DivisionByZero(insn),
+        _ => return Err(invalid()),
+    }))
+}
+
+/// Runs every case in `text` on every engine.
+fn check(family: &str, text: &str) {
+    let cases = parse(family, text);
+    assert!(!cases.is_empty(), "{family}.test has no cases");
+    for case in cases {
+        let at = format!("{family}.test:{} {}", case.line, case.name);
+        let run = |tier| run(&case.funcs, case.regs, &case.mem, tier);
+        let expected = run(Tier::Interpreted);
+        for &(reg, value) in &case.expect_regs {
+            assert_eq!(expected.regs[reg], value, "{at}: r{reg}");
+        }
+        for &(addr, value) in &case.expect_mem {
+            let bytes = &expected.mem[addr as usize..addr as usize + 8];
+            let actual = i64::from_le_bytes(bytes.try_into().unwrap());
+            assert_eq!(actual, value, "{at}: mem 0x{addr:04x}");
+        }
+        assert_eq!(expected.printed, case.expect_output, "{at}: output");
+        assert_eq!(expected.trapped, case.expect_trap, "{at}: trap");
+        let all = vec![true; case.funcs.len()];
+        for tier in [Tier::Stepping, Tier::Compiled(&all), Tier::Speculated] {
+            let actual = run(tier);
+            let at = format!("{at} ({tier:?} on {})", std::env::consts::ARCH);
+            assert_eq!(actual.regs, expected.regs, "{at}: registers");
+            if let Some(addr) = (0..MEM_SIZE).find(|&addr| actual.mem[addr] != expected.mem[addr]) {
+                panic!("{at}: mem differs first at 0x{addr:04x}");
+            }
+            assert_eq!(actual.printed, expected.printed, "{at}: output");
+            assert_eq!(actual.trapped, expected.trapped, "{at}: trap");
+            if !matches!(tier, Tier::Stepping) {
+                assert_eq!(
+                    !actual.rejected.is_empty(),
+                    case.expect_rejected,
+                    "{at}: rejected functions {:?}",
+                    actual.rejected
+                );
+            }
+        }
+    }
+}
+
+macro_rules! families {
+    ($($family:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $family() {
+                check(
+                    stringify!($family),
+                    include_str!(concat!("../conformance/", stringify!($family), ".test")),
+                );
+            }
+        )*
+    };
+}
+
+families!(
+    moves, arithmetic, division, memory, jumps, calls, output, traps, float, checked, wide,
+    branches, switch,
+);
@@ -0,0 +1,335 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    ir::Frame,
+    opcodes::{mnemonic, CALL},
+    runtime::{Context, Func},
+};
+
+/// Hook the interpreter consults before every instruction while `Context::debugger` is set.
+pub trait DebugHook {
+    /// Called before the interpreter executes the instruction at `ctx.pc`.
+    ///
+    /// Returns `false` if execution should be aborted.
+    fn before_step(&mut self, ctx: &mut Context) -> bool;
+}
+
+/// How far execution continues before the debugger prompts again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resume {
+    /// Stop before the next instruction.
+    Step,
+    /// Stop once `pc` is reached with `depth` entries on the callstack, stepping over a call.
+    Next { pc: *const u16, depth: usize },
+    /// Stop at breakpoints and watchpoints only.
+    Continue,
+}
+
+/// Interactive debugger for the interpreter, enabled by setting `Context::debugger`.
+///
+/// Before every instruction the interpreter executes, the debugger checks its breakpoints and
+/// watchpoints and prompts for commands once one of them or a step finishes. Compiled code
+/// runs without interruption, so setting a breakpoint deoptimizes all compiled functions.
+/// Calls into native code are stepped over as a whole.
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    resume: Resume,
+    breakpoints: BTreeSet<(usize, usize)>,
+    /// Watched `mem` addresses and the value last seen at each of them.
+    watchpoints: BTreeMap<u16, u64>,
+}
+
+impl Default for Debugger {
+    /// Reads commands from stdin and writes to stdout.
+    fn default() -> Self {
+        Self::new(Box::new(io::stdin().lock()), Box::new(io::stdout()))
+    }
+}
+
+impl Debugger {
+    /// Creates a debugger that stops before the first instruction.
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            resume: Resume::Step,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Reads and executes commands until one resumes execution.
+    fn prompt(&mut self, ctx: &mut Context) -> io::Result<bool> {
+        loop {
+            write!(self.output, "(debug) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // Detach, letting the program run to completion.
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                self.resume = Resume::Continue;
+                return Ok(true);
+            }
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            match args[..] {
+                [] => {}
+                ["s" | "step"] => {
+                    self.resume = Resume::Step;
+                    return Ok(true);
+                }
+                ["n" | "next"] => {
+                    let insn = unsafe { *ctx.pc };
+                    self.resume = if insn & 0xf000 == CALL {
+                        Resume::Next {
+                            pc: unsafe { ctx.pc.add(1) },
+                            depth: ctx.callstack.entries().len(),
+                        }
+                    } else {
+                        Resume::Step
+                    };
+                    return Ok(true);
+                }
+                ["c" | "continue"] => {
+                    self.resume = Resume::Continue;
+                    return Ok(true);
+                }
+                ["q" | "quit"] => return Ok(false),
+                ["b" | "break", func, ref offset @ ..] => {
+                    let Some(frame) = parse_location(ctx, func, offset) else {
+                        writeln!(self.output, "Invalid location")?;
+                        continue;
+                    };
+                    if let Err(err) = ctx.deoptimize_all() {
+                        writeln!(self.output, "{err}")?;
+                    }
+                    self.breakpoints.insert((frame.func, frame.pc));
+                    writeln!(self.output, "Breakpoint set at {}", symbol(ctx, frame))?;
+                }
+                ["d" | "delete", func, ref offset @ ..] => {
+                    let removed = parse_location(ctx, func, offset)
+                        .is_some_and(|frame| self.breakpoints.remove(&(frame.func, frame.pc)));
+                    if !removed {
+                        writeln!(self.output, "No such breakpoint")?;
+                    }
+                }
+                ["w" | "watch", addr] => {
+                    let Some(addr) = parse_number(addr).and_then(|addr| u16::try_from(addr).ok())
+                    else {
+                        writeln!(self.output, "Invalid address")?;
+                        continue;
+                    };
+                    self.watchpoints.insert(addr, read(ctx, addr));
+                    writeln!(self.output, "Watching 0x{addr:04x}")?;
+                }
+                ["unwatch", addr] => {
+                    let removed = parse_number(addr)
+                        .and_then(|addr| u16::try_from(addr).ok())
+                        .is_some_and(|addr| self.watchpoints.remove(&addr).is_some());
+                    if !removed {
+                        writeln!(self.output, "No such watchpoint")?;
+                    }
+                }
+                ["r" | "regs"] => {
+                    for (index, reg) in ctx.regs.iter().enumerate() {
+                        let value = unsafe { reg.int };
+                        writeln!(self.output, "r{index} = {value} (0x{value:016x})")?;
+                    }
+                }
+                ["x" | "mem", addr, ref count @ ..] => {
+                    let addr = parse_number(addr).and_then(|addr| u16::try_from(addr).ok());
+                    let count = match count {
+                        [] => Some(1),
+                        [count] => parse_number(count),
+                        _ => None,
+                    };
+                    let (Some(addr), Some(count)) = (addr, count) else {
+                        writeln!(self.output, "Usage: mem <addr> [count]")?;
+                        continue;
+                    };
+                    for index in 0..count {
+                        let Some(addr) = u16::try_from(index * 8)
+                            .ok()
+                            .and_then(|offset| addr.checked_add(offset))
+                        else {
+                            break;
+                        };
+                        writeln!(self.output, "0x{addr:04x}: {}", read(ctx, addr) as i64)?;
+                    }
+                }
+                ["bt" | "backtrace"] => write!(self.output, "{}", ctx.format_backtrace())?,
+                ["h" | "help"] => writeln!(self.output, "{HELP}")?,
+                _ => writeln!(self.output, "Unknown command, try \"help\"")?,
+            }
+        }
+    }
+
+    /// Prints the instruction about to be executed.
+    fn show(&mut self, ctx: &Context) {
+        let insn = unsafe { *ctx.pc };
+        let location = match Func::locate(&ctx.funcs, ctx.pc) {
+            Some(frame) => symbol(ctx, frame),
+            None => format!("{:p}", ctx.pc),
+        };
+        self.say(format_args!("{location}: {:04x} {}", insn, mnemonic(insn)));
+    }
+
+    fn say(&mut self, message: std::fmt::Arguments) {
+        writeln!(self.output, "{message}").ok();
+    }
+}
+
+impl DebugHook for Debugger {
+    fn before_step(&mut self, ctx: &mut Context) -> bool {
+        let mut stop = match self.resume {
+            Resume::Step => true,
+            Resume::Next { pc, depth } => pc == ctx.pc && depth == ctx.callstack.entries().len(),
+            Resume::Continue => false,
+        };
+        let location = Func::locate(&ctx.funcs, ctx.pc);
+        if let Some(frame) = location {
+            if self.breakpoints.contains(&(frame.func, frame.pc)) {
+                stop = true;
+                self.say(format_args!("Breakpoint at {}", symbol(ctx, frame)));
+            }
+        }
+        for (&addr, old) in &mut self.watchpoints {
+            let new = read(ctx, addr);
+            if new != *old {
+                stop = true;
+                writeln!(self.output, "Watchpoint 0x{addr:04x}: {old} -> {new}").ok();
+                *old = new;
+            }
+        }
+        if !stop {
+            return true;
+        }
+        self.show(ctx);
+        self.prompt(ctx).unwrap_or(false)
+    }
+}
+
+const HELP: &str = "\
+step, s                   execute one instruction
+next, n                   execute one instruction, stepping over calls
+continue, c               run until a breakpoint or watchpoint is hit
+break, b <func> [offset]  stop before the instruction at offset in func (index or name)
+delete, d <func> [offset] remove a breakpoint
+watch, w <addr>           stop after the 64-bit value at addr in mem changes
+unwatch <addr>            remove a watchpoint
+regs, r                   print the registers
+mem, x <addr> [count]     print count 64-bit values starting at addr in mem
+backtrace, bt             print the callstack
+quit, q                   abort execution";
+
+fn symbol(ctx: &Context, frame: Frame) -> String {
+    format!("{}+{}", Func::symbol(&ctx.funcs, frame.func), frame.pc)
+}
+
+fn read(ctx: &Context, addr: u16) -> u64 {
+    unsafe { (ctx.mem.add(addr as usize) as *const u64).read_unaligned() }
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses a function given by index or name and an optional offset into it.
+fn parse_location(ctx: &Context, func: &str, offset: &[&str]) -> Option<Frame> {
+    let func = parse_number(func)
+        .or_else(|| {
+            (0..ctx.funcs.len()).find(|&index| ctx.funcs[index].name.as_deref() == Some(func))
+        })
+        .filter(|&func| func < ctx.funcs.len())?;
+    let pc = match offset {
+        [] => 0,
+        [offset] => parse_number(offset)?,
+        _ => return None,
+    };
+    (pc < ctx.funcs[func].code.len()).then_some(Frame { func, pc })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{Cursor, Write},
+        rc::Rc,
+    };
+
+    use super::Debugger;
+    use crate::{
+        opcodes::{__call, __iload, __load, __memstore, __return},
+        runtime::{Context, Func, Runner},
+    };
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debugger_commands() {
+        let mut ctx = Context::default();
+        let mut runner = Runner::default();
+        ctx.funcs
+            .push(Func::named("main", vec![__call(1), __call(1), __return()]));
+        ctx.funcs.push(Func::named(
+            "store",
+            vec![__load(0, 8), __iload(1, 5), __memstore(0, 1), __return()],
+        ));
+        ctx.compile(1).unwrap();
+        ctx.pc = ctx.funcs[0].addr.address as *const u16;
+        unsafe { (ctx.mem.add(8) as *mut u64).write_unaligned(0) };
+        let commands = "\
+            watch 8\n\
+            next\n\
+            break store 2\n\
+            continue\n\
+            backtrace\n\
+            mem 8\n\
+            delete store 2\n\
+            step\n\
+            quit\n";
+        let output = Output::default();
+        ctx.debugger = Some(Box::new(Debugger::new(
+            Box::new(Cursor::new(commands)),
+            Box::new(output.clone()),
+        )));
+        runner.run(&mut ctx);
+        let output = String::from_utf8(output.0.take()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "main+0: e001 CALL",
+                "(debug) Watching 0x0008",
+                "(debug) Watchpoint 0x0008: 0 -> 5",
+                "main+1: e001 CALL",
+                "(debug) Breakpoint set at store+2",
+                "(debug) Breakpoint at store+2",
+                "store+2: 0308 MEMSTORE",
+                "(debug) #0 store+2",
+                "#1 main+1",
+                "(debug) 0x0008: 5",
+                "(debug) (debug) store+3: 0400 RETURN",
+                "(debug) ",
+            ]
+        );
+        assert!(ctx.code.get(1).is_none());
+    }
+}
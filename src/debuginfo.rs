@@ -0,0 +1,405 @@
+use std::{
+    ptr::{addr_of_mut, null_mut},
+    sync::Mutex,
+};
+
+use crate::cache::Code;
+
+/// `e_machine` of the host in ELF files.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const ELF_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+pub(crate) const ELF_MACHINE: u16 = 183;
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+/// Node of the list of in-memory object files gdb reads, see "JIT Interface" in the gdb
+/// manual.
+#[repr(C)]
+struct JitCodeEntry {
+    next: *mut JitCodeEntry,
+    prev: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// Read by gdb whenever [`__jit_debug_register_code`] is called.
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: null_mut(),
+    first_entry: null_mut(),
+};
+
+/// Serializes changes to `__jit_debug_descriptor`.
+static DESCRIPTOR: Mutex<()> = Mutex::new(());
+
+/// gdb sets a breakpoint here to learn about changes to `__jit_debug_descriptor`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    std::hint::black_box(());
+}
+
+/// Compiled code registered with gdb, unregistered when dropped.
+pub struct Registration {
+    entry: Box<JitCodeEntry>,
+    _elf: Vec<u8>,
+}
+
+// The entry is only accessed while holding `DESCRIPTOR`.
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    /// Makes `code` known to an attached gdb under the symbol `name`.
+    ///
+    /// `file` names the bytecode of a function. Native code is mapped to lines of these files,
+    /// where line `n` is the instruction at offset `n - 1`.
+    pub fn new(code: &Code, name: &str, file: impl Fn(usize) -> String) -> Self {
+        let elf = elf(code, name, file);
+        let mut entry = Box::new(JitCodeEntry {
+            next: null_mut(),
+            prev: null_mut(),
+            symfile_addr: elf.as_ptr(),
+            symfile_size: elf.len() as u64,
+        });
+        let _lock = DESCRIPTOR.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            entry.next = (*descriptor).first_entry;
+            if let Some(next) = entry.next.as_mut() {
+                next.prev = &mut *entry;
+            }
+            (*descriptor).first_entry = &mut *entry;
+            (*descriptor).relevant_entry = &mut *entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+        }
+        __jit_debug_register_code();
+        Self { entry, _elf: elf }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = DESCRIPTOR.lock().unwrap();
+        unsafe {
+            let descriptor = addr_of_mut!(__jit_debug_descriptor);
+            match self.entry.prev.as_mut() {
+                Some(prev) => prev.next = self.entry.next,
+                None => (*descriptor).first_entry = self.entry.next,
+            }
+            if let Some(next) = self.entry.next.as_mut() {
+                next.prev = self.entry.prev;
+            }
+            (*descriptor).relevant_entry = &mut *self.entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+        }
+        __jit_debug_register_code();
+    }
+}
+
+/// Section indices of the object files built by [`elf`].
+const TEXT: u16 = 1;
+const STRTAB: u32 = 6;
+const SHSTRTAB: u16 = 7;
+
+/// Header fields and contents of a section, see `Elf64_Shdr`.
+#[derive(Default)]
+struct Section<'a> {
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    /// Size of `SHT_NOBITS` sections, which have no `data`.
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+    data: &'a [u8],
+}
+
+/// Builds an object file describing `code`: a `.text` section without contents at the address
+/// of the code, a symbol covering all of it, and DWARF line info.
+fn elf(code: &Code, name: &str, file: impl Fn(usize) -> String) -> Vec<u8> {
+    let addr = code.buf.as_ptr() as u64;
+    let size = code.buf.len() as u64;
+    let mut strtab = vec![0];
+    strtab.extend(name.as_bytes());
+    strtab.push(0);
+    let mut symtab = vec![0; 24];
+    symtab.extend(1u32.to_le_bytes()); // st_name
+    symtab.push(0x12); // STB_GLOBAL, STT_FUNC
+    symtab.push(0);
+    symtab.extend(TEXT.to_le_bytes());
+    symtab.extend(addr.to_le_bytes());
+    symtab.extend(size.to_le_bytes());
+    let (abbrev, info, line) = dwarf(code, name, file);
+
+    let names = [
+        "",
+        ".text",
+        ".debug_abbrev",
+        ".debug_info",
+        ".debug_line",
+        ".symtab",
+        ".strtab",
+        ".shstrtab",
+    ];
+    let mut shstrtab = Vec::new();
+    let mut name_offsets = Vec::with_capacity(names.len());
+    for name in names {
+        name_offsets.push(shstrtab.len() as u32);
+        shstrtab.extend(name.as_bytes());
+        shstrtab.push(0);
+    }
+    let progbits = |data| Section {
+        kind: 1,
+        data,
+        ..Section::default()
+    };
+    let sections = [
+        Section::default(),
+        Section {
+            kind: 8,    // SHT_NOBITS
+            flags: 0x6, // SHF_ALLOC | SHF_EXECINSTR
+            addr,
+            size,
+            align: 16,
+            ..Section::default()
+        },
+        progbits(&abbrev),
+        progbits(&info),
+        progbits(&line),
+        Section {
+            kind: 2, // SHT_SYMTAB
+            link: STRTAB,
+            info: 1,
+            align: 8,
+            entsize: 24,
+            data: &symtab,
+            ..Section::default()
+        },
+        Section {
+            kind: 3, // SHT_STRTAB
+            data: &strtab,
+            ..Section::default()
+        },
+        Section {
+            kind: 3,
+            data: &shstrtab,
+            ..Section::default()
+        },
+    ];
+
+    let mut elf = Vec::new();
+    elf.extend(b"\x7fELF");
+    elf.extend([2, 1, 1, 0]); // 64-bit, little endian, version 1, System V ABI
+    elf.extend([0; 8]);
+    elf.extend(2u16.to_le_bytes()); // ET_EXEC
+    elf.extend(ELF_MACHINE.to_le_bytes());
+    elf.extend(1u32.to_le_bytes());
+    elf.extend(0u64.to_le_bytes()); // e_entry
+    elf.extend(0u64.to_le_bytes()); // e_phoff
+    let shoff_at = elf.len();
+    elf.extend(0u64.to_le_bytes()); // e_shoff
+    elf.extend(0u32.to_le_bytes()); // e_flags
+    elf.extend(64u16.to_le_bytes()); // e_ehsize
+    elf.extend(0u16.to_le_bytes()); // e_phentsize
+    elf.extend(0u16.to_le_bytes()); // e_phnum
+    elf.extend(64u16.to_le_bytes()); // e_shentsize
+    elf.extend((sections.len() as u16).to_le_bytes());
+    elf.extend(SHSTRTAB.to_le_bytes());
+
+    let mut offsets = Vec::with_capacity(sections.len());
+    for section in &sections {
+        while elf.len() % 8 != 0 {
+            elf.push(0);
+        }
+        offsets.push(elf.len() as u64);
+        elf.extend(section.data);
+    }
+    while elf.len() % 8 != 0 {
+        elf.push(0);
+    }
+    let shoff = elf.len() as u64;
+    elf[shoff_at..shoff_at + 8].copy_from_slice(&shoff.to_le_bytes());
+    for (index, section) in sections.iter().enumerate() {
+        let size = match section.kind {
+            8 => section.size,
+            _ => section.data.len() as u64,
+        };
+        let offset = if index == 0 { 0 } else { offsets[index] };
+        elf.extend(name_offsets[index].to_le_bytes());
+        elf.extend(section.kind.to_le_bytes());
+        elf.extend(section.flags.to_le_bytes());
+        elf.extend(section.addr.to_le_bytes());
+        elf.extend(offset.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(section.link.to_le_bytes());
+        elf.extend(section.info.to_le_bytes());
+        elf.extend(section.align.to_le_bytes());
+        elf.extend(section.entsize.to_le_bytes());
+    }
+    elf
+}
+
+/// Builds the `.debug_abbrev`, `.debug_info` and `.debug_line` sections of a single DWARF 2
+/// compilation unit covering `code`.
+fn dwarf(code: &Code, name: &str, file: impl Fn(usize) -> String) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let low = code.buf.as_ptr() as u64;
+    let high = low + code.buf.len() as u64;
+
+    // DW_TAG_compile_unit without children: DW_AT_name (string), DW_AT_stmt_list (data4),
+    // DW_AT_low_pc (addr), DW_AT_high_pc (addr).
+    let abbrev = vec![
+        1, 0x11, 0, 0x03, 0x08, 0x10, 0x06, 0x11, 0x01, 0x12, 0x01, 0, 0, 0,
+    ];
+
+    let mut info = Vec::new();
+    info.extend(0u32.to_le_bytes()); // unit_length
+    info.extend(2u16.to_le_bytes()); // version
+    info.extend(0u32.to_le_bytes()); // debug_abbrev_offset
+    info.push(8); // address_size
+    info.push(1);
+    info.extend(name.as_bytes());
+    info.push(0);
+    info.extend(0u32.to_le_bytes());
+    info.extend(low.to_le_bytes());
+    info.extend(high.to_le_bytes());
+    let length = info.len() as u32 - 4;
+    info[..4].copy_from_slice(&length.to_le_bytes());
+
+    let mut files = Vec::new();
+    let mut program = Vec::new();
+    program.extend([0, 9, 2]); // DW_LNE_set_address
+    program.extend(low.to_le_bytes());
+    let (mut offset, mut line, mut current) = (0, 1, 1);
+    let mut previous = None;
+    for &(at, frame) in &code.lines {
+        let index = match files.iter().position(|&func| func == frame.func) {
+            Some(index) => index + 1,
+            None => {
+                files.push(frame.func);
+                files.len()
+            }
+        };
+        let next = frame.pc as i64 + 1;
+        if previous == Some((index, next)) {
+            continue;
+        }
+        previous = Some((index, next));
+        if index != current {
+            program.push(4); // DW_LNS_set_file
+            uleb128(&mut program, index as u64);
+            current = index;
+        }
+        program.push(2); // DW_LNS_advance_pc
+        uleb128(&mut program, (at - offset) as u64);
+        program.push(3); // DW_LNS_advance_line
+        sleb128(&mut program, next - line);
+        program.push(1); // DW_LNS_copy
+        offset = at;
+        line = next;
+    }
+    program.push(2);
+    uleb128(&mut program, code.buf.len() as u64 - offset as u64);
+    program.extend([0, 1, 1]); // DW_LNE_end_sequence
+
+    let mut header = vec![1, 1, -5i8 as u8, 14, 13]; // min_inst_length .. opcode_base
+    header.extend([0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]); // standard_opcode_lengths
+    header.push(0); // include_directories
+    for func in files {
+        header.extend(file(func).as_bytes());
+        header.extend([0, 0, 0, 0]);
+    }
+    header.push(0);
+    let mut line = Vec::new();
+    line.extend((2 + 4 + header.len() as u32 + program.len() as u32).to_le_bytes());
+    line.extend(2u16.to_le_bytes());
+    line.extend((header.len() as u32).to_le_bytes());
+    line.extend(header);
+    line.extend(program);
+
+    (abbrev, info, line)
+}
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::addr_of;
+
+    use super::{__jit_debug_descriptor, Registration, DESCRIPTOR};
+    use crate::{cache::Code, ir::Frame};
+
+    #[test]
+    fn test_register_with_gdb() {
+        let mut buf = dynasmrt::mmap::MutableBuffer::new(16).unwrap();
+        buf.set_len(16);
+        let code = Code {
+            func: 0,
+            buf: buf.make_exec().unwrap(),
+            returns: Vec::new(),
+            lines: vec![(0, Frame { func: 0, pc: 0 }), (8, Frame { func: 1, pc: 2 })],
+            debug: None,
+            counter: None,
+        };
+        let registration = Registration::new(&code, "func0", |func| format!("func{func}"));
+        let elf = unsafe {
+            std::slice::from_raw_parts(
+                registration.entry.symfile_addr,
+                registration.entry.symfile_size as usize,
+            )
+        };
+        assert_eq!(elf[..4], *b"\x7fELF");
+        assert!(elf.windows(6).any(|name| name == b"func1\0"));
+        let registered = |entry| {
+            let _lock = DESCRIPTOR.lock().unwrap();
+            let mut next = unsafe { (*addr_of!(__jit_debug_descriptor)).first_entry };
+            while !next.is_null() {
+                if std::ptr::eq(next, entry) {
+                    return true;
+                }
+                next = unsafe { (*next).next };
+            }
+            false
+        };
+        let entry = &*registration.entry as *const _;
+        assert!(registered(entry));
+        drop(registration);
+        assert!(!registered(entry));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{
+    ir::Cond,
+    opcodes::{
+        ADD, ADDO, BRANCH, CALL, CHECKEDOP, DIV, DIVREM, FADD, FCMP, FDIV, FLOATOP, FMUL, FSUB,
+        FTOI, IADDO, IDIV, IDIVREM, ILOAD, IMUL, IMULO, IREM, ISUBO, ITOF, JUMP, JUMPNZ, JUMPZ,
+        LJUMP, LOAD, MEMLOAD, MEMSTORE, MOVE, MUL, MULH, MULHU, MULO, NOOP, REM, RETURN, SMALLOP,
+        SUB, SUBO, SWITCH, WIDEOP,
+    },
+    runtime::{long_offset, sign_extend},
+};
+
+/// A bytecode instruction decoded for the interpreter's fast path, see `Context::interpret`.
+///
+/// Operands are extracted and jump targets resolved to offsets into the function, so an
+/// instruction is a single dispatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Noop,
+    Move {
+        dst: u8,
+        src: u8,
+    },
+    MemLoad {
+        dst: u8,
+        src: u8,
+    },
+    MemStore {
+        dst: u8,
+        src: u8,
+    },
+    Return,
+    Add {
+        dst: u8,
+        src: u8,
+    },
+    Sub {
+        dst: u8,
+        src: u8,
+    },
+    Mul {
+        dst: u8,
+        src: u8,
+    },
+    Imul {
+        dst: u8,
+        src: u8,
+    },
+    Div {
+        dst: u8,
+        src: u8,
+    },
+    Idiv {
+        dst: u8,
+        src: u8,
+    },
+    Rem {
+        dst: u8,
+        src: u8,
+    },
+    Irem {
+        dst: u8,
+        src: u8,
+    },
+    FAdd {
+        dst: u8,
+        src: u8,
+    },
+    FSub {
+        dst: u8,
+        src: u8,
+    },
+    FMul {
+        dst: u8,
+        src: u8,
+    },
+    FDiv {
+        dst: u8,
+        src: u8,
+    },
+    FCmp {
+        dst: u8,
+        src: u8,
+    },
+    IntToFloat {
+        dst: u8,
+        src: u8,
+    },
+    FloatToInt {
+        dst: u8,
+        src: u8,
+    },
+    /// Checked arithmetic, leaving overflows to `Context::step`.
+    AddO {
+        dst: u8,
+        src: u8,
+    },
+    IAddO {
+        dst: u8,
+        src: u8,
+    },
+    SubO {
+        dst: u8,
+        src: u8,
+    },
+    ISubO {
+        dst: u8,
+        src: u8,
+    },
+    MulO {
+        dst: u8,
+        src: u8,
+    },
+    IMulO {
+        dst: u8,
+        src: u8,
+    },
+    MulH {
+        dst: u8,
+        src: u8,
+    },
+    MulHU {
+        dst: u8,
+        src: u8,
+    },
+    /// `DIVREM` and `IDIVREM`, leaving traps to `Context::step`.
+    DivRem {
+        dst: u8,
+        rem: u8,
+        src: u8,
+    },
+    IDivRem {
+        dst: u8,
+        rem: u8,
+        src: u8,
+    },
+    /// `LOAD` and `ILOAD`, with the immediate extended accordingly.
+    Load {
+        dst: u8,
+        value: i16,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpZ {
+        cond: u8,
+        target: u32,
+    },
+    JumpNz {
+        cond: u8,
+        target: u32,
+    },
+    /// A branch, continuing behind its offset word if not taken.
+    Branch {
+        cond: Cond,
+        a: u8,
+        b: u8,
+        target: u32,
+    },
+    /// `SWITCH` whose offsets and default all stay inside the function.
+    Switch {
+        reg: u8,
+        len: u16,
+    },
+    Call {
+        func: u16,
+    },
+    /// Left to `Context::step`: `PRINT`, `PRINTF`, `HALT`, invalid instructions, jumps out of
+    /// the function and running past its end.
+    Slow,
+}
+
+/// Decodes every instruction of `code`, followed by an [`Op::Slow`] for running past its end.
+pub fn decode(code: &[u16]) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(code.len() + 1);
+    for (pc, &insn) in code.iter().enumerate() {
+        let dst = (insn & 0x7) as u8;
+        let src = ((insn & 0x38) >> 3) as u8;
+        let target = |offset: i64| {
+            let target = pc as i64 + offset;
+            (0..code.len() as i64)
+                .contains(&target)
+                .then_some(target as u32)
+        };
+        let op = match insn & 0xf000 {
+            SMALLOP => match insn & 0xf00 {
+                NOOP => Op::Noop,
+                MOVE => Op::Move { dst, src },
+                MEMLOAD => Op::MemLoad { dst, src },
+                MEMSTORE => Op::MemStore { dst, src },
+                RETURN => Op::Return,
+                ADD => Op::Add { dst, src },
+                SUB => Op::Sub { dst, src },
+                MUL => Op::Mul { dst, src },
+                IMUL => Op::Imul { dst, src },
+                DIV => Op::Div { dst, src },
+                IDIV => Op::Idiv { dst, src },
+                REM => Op::Rem { dst, src },
+                IREM => Op::Irem { dst, src },
+                _ => Op::Slow,
+            },
+            FLOATOP => match insn & 0xff00 {
+                FADD => Op::FAdd { dst, src },
+                FSUB => Op::FSub { dst, src },
+                FMUL => Op::FMul { dst, src },
+                FDIV => Op::FDiv { dst, src },
+                FCMP => Op::FCmp { dst, src },
+                ITOF => Op::IntToFloat { dst, src },
+                FTOI => Op::FloatToInt { dst, src },
+                _ => Op::Slow,
+            },
+            WIDEOP => {
+                let rem = ((insn & 0x1c0) >> 6) as u8;
+                match insn & 0xfe00 {
+                    MULH => Op::MulH { dst, src },
+                    MULHU => Op::MulHU { dst, src },
+                    DIVREM => Op::DivRem { dst, rem, src },
+                    IDIVREM => Op::IDivRem { dst, rem, src },
+                    _ => Op::Slow,
+                }
+            }
+            CHECKEDOP => match insn & 0xff00 {
+                ADDO => Op::AddO { dst, src },
+                IADDO => Op::IAddO { dst, src },
+                SUBO => Op::SubO { dst, src },
+                ISUBO => Op::ISubO { dst, src },
+                MULO => Op::MulO { dst, src },
+                IMULO => Op::IMulO { dst, src },
+                _ => Op::Slow,
+            },
+            LOAD => Op::Load {
+                dst,
+                value: ((insn & 0xff8) >> 3) as i16,
+            },
+            ILOAD => Op::Load {
+                dst,
+                value: sign_extend::<9>((insn & 0xff8) >> 3) as i16,
+            },
+            JUMP => match target(sign_extend::<12>(insn & 0xfff)) {
+                Some(target) => Op::Jump { target },
+                None => Op::Slow,
+            },
+            LJUMP => match code
+                .get(pc + 1)
+                .and_then(|&low| target(long_offset(insn, low)))
+            {
+                Some(target) => Op::Jump { target },
+                None => Op::Slow,
+            },
+            JUMPZ => match target(sign_extend::<9>((insn & 0xff8) >> 3)) {
+                Some(target) => Op::JumpZ { cond: dst, target },
+                None => Op::Slow,
+            },
+            JUMPNZ => match target(sign_extend::<9>((insn & 0xff8) >> 3)) {
+                Some(target) => Op::JumpNz { cond: dst, target },
+                None => Op::Slow,
+            },
+            BRANCH => match (
+                Cond::of(insn),
+                code.get(pc + 1)
+                    .and_then(|&offset| target(offset as i16 as i64)),
+            ) {
+                (Some(cond), Some(target)) => Op::Branch {
+                    cond,
+                    a: dst,
+                    b: src,
+                    target,
+                },
+                _ => Op::Slow,
+            },
+            SWITCH => {
+                let len = (insn & 0xff8) >> 3;
+                let resolved = code
+                    .get(pc + 1..pc + 1 + len as usize)
+                    .is_some_and(|table| {
+                        table
+                            .iter()
+                            .map(|&offset| offset as i16 as i64)
+                            .chain([len as i64 + 1])
+                            .all(|offset| target(offset).is_some())
+                    });
+                if resolved {
+                    Op::Switch { reg: dst, len }
+                } else {
+                    Op::Slow
+                }
+            }
+            CALL => Op::Call { func: insn & 0xfff },
+            _ => Op::Slow,
+        };
+        ops.push(op);
+    }
+    ops.push(Op::Slow);
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Op};
+    use crate::{
+        ir::Cond,
+        opcodes::{
+            __bge, __bltu, __bne, __call, __divrem, __fadd, __fcmp, __ftoi, __idivrem, __iload,
+            __itof, __jump, __jumpnz, __load, __mulhu, __print, __printf, __return, __sub,
+            __switch,
+        },
+    };
+
+    #[test]
+    fn test_decode() {
+        let code = [
+            __load(1, 300),
+            __iload(2, -3),
+            __sub(1, 2),
+            __jumpnz(1, -1),
+            __jump(4),
+            __print(1),
+            __call(7),
+            __return(),
+        ];
+        assert_eq!(
+            decode(&code),
+            [
+                Op::Load { dst: 1, value: 300 },
+                Op::Load { dst: 2, value: -3 },
+                Op::Sub { dst: 1, src: 2 },
+                Op::JumpNz { cond: 1, target: 2 },
+                Op::Slow,
+                Op::Slow,
+                Op::Call { func: 7 },
+                Op::Return,
+                Op::Slow,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_floats() {
+        let code = [
+            __fadd(1, 2),
+            __fcmp(3, 4),
+            __itof(5, 6),
+            __ftoi(7, 0),
+            __printf(1),
+        ];
+        assert_eq!(
+            decode(&code),
+            [
+                Op::FAdd { dst: 1, src: 2 },
+                Op::FCmp { dst: 3, src: 4 },
+                Op::IntToFloat { dst: 5, src: 6 },
+                Op::FloatToInt { dst: 7, src: 0 },
+                Op::Slow,
+                Op::Slow,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_wide() {
+        let code = [__mulhu(1, 2), __divrem(3, 4, 5), __idivrem(6, 6, 7), 0x5e00];
+        assert_eq!(
+            decode(&code),
+            [
+                Op::MulHU { dst: 1, src: 2 },
+                Op::DivRem {
+                    dst: 3,
+                    rem: 4,
+                    src: 5
+                },
+                Op::IDivRem {
+                    dst: 6,
+                    rem: 6,
+                    src: 7
+                },
+                Op::Slow,
+                Op::Slow,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_branches() {
+        let code = [
+            __bltu(1, 2, 6),
+            __bge(3, 4, -2),
+            __bne(5, 6, -5),
+            [__return(), 0],
+        ]
+        .concat();
+        let ops = decode(&code);
+        assert_eq!(
+            ops[0],
+            Op::Branch {
+                cond: Cond::LtU,
+                a: 1,
+                b: 2,
+                target: 6
+            }
+        );
+        assert_eq!(
+            ops[2],
+            Op::Branch {
+                cond: Cond::Ge,
+                a: 3,
+                b: 4,
+                target: 0
+            }
+        );
+        // The third branch jumps out of the function.
+        assert_eq!(ops[4], Op::Slow);
+        assert_eq!(ops[6], Op::Return);
+    }
+
+    #[test]
+    fn test_decode_switch() {
+        let code = [__switch(3, 2), 4, 5, __switch(4, 1), 9, __return()];
+        let ops = decode(&code);
+        assert_eq!(ops[0], Op::Switch { reg: 3, len: 2 });
+        // The second table jumps out of the function.
+        assert_eq!(ops[3], Op::Slow);
+    }
+}
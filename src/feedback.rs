@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+/// Values the interpreter observed in a register at one instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Observed {
+    #[default]
+    Nothing,
+    Const(i64),
+    Varying,
+}
+
+/// Feedback collected for one instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Site {
+    /// Number of times the instruction was executed.
+    pub count: u32,
+    /// Divisor of divisions and remainders.
+    pub value: Observed,
+}
+
+/// Execution feedback the interpreter collects for the JIT, keyed by instruction address.
+///
+/// Collected while `Context::feedback` is set. [`crate::opt::speculate`] uses it to replace
+/// instructions with guarded specialized versions.
+#[derive(Debug, Default)]
+pub struct Feedback {
+    sites: HashMap<*const u16, Site>,
+}
+
+impl Feedback {
+    /// Number of executions after which feedback is trusted.
+    pub const MIN_COUNT: u32 = 16;
+
+    pub fn get(&self, pc: *const u16) -> Option<&Site> {
+        self.sites.get(&pc)
+    }
+
+    pub fn record_value(&mut self, pc: *const u16, value: i64) {
+        let site = self.sites.entry(pc).or_default();
+        site.count = site.count.saturating_add(1);
+        site.value = match site.value {
+            Observed::Nothing => Observed::Const(value),
+            Observed::Const(old) if old == value => Observed::Const(value),
+            _ => Observed::Varying,
+        };
+    }
+}
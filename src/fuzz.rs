@@ -0,0 +1,229 @@
+//! Differential fuzzing of the interpreter against compiled code.
+//!
+//! Programs are generated from [`Unit`]s that only fault by overflowing checked arithmetic:
+//! divisors are nonzero constants, loops are counted and calls only go to functions with a higher
+//! index. Each program runs purely interpreted, with a random subset of its functions compiled, and
+//! compiled with speculation on feedback from a previous interpreted run. All runs have to agree on
+//! the registers, memory, printed numbers and trap. Failing programs are shrunk by proptest and
+//! reported with their bytecode. Set `PROPTEST_CASES` to run more programs than the default.
+
+use proptest::{collection::vec, prelude::*};
+
+use crate::{
+    builder::Builder,
+    harness::{run, Tier},
+    opcodes::{
+        __add, __addo, __beq, __bge, __bgeu, __blt, __bltu, __bne, __call, __div, __divrem, __fadd,
+        __fcmp, __fdiv, __fmul, __fsub, __ftoi, __halt, __iaddo, __idiv, __idivrem, __iload,
+        __imul, __imulo, __irem, __isubo, __itof, __jump, __jumpnz, __jumpz, __load, __memload,
+        __memstore, __move, __mul, __mulh, __mulhu, __mulo, __print, __printf, __rem, __return,
+        __sub, __subo, mnemonic,
+    },
+};
+
+/// Register counting loop iterations. Only loops write it.
+const COUNTER: u16 = 6;
+/// Register holding divisors and loop decrements. Only the units using them write it.
+const SCRATCH: u16 = 7;
+
+/// A piece of a generated function, expanding to one or more instructions.
+#[derive(Clone, Debug)]
+enum Unit {
+    Load(u16, u16),
+    ILoad(u16, i16),
+    Move(u16, u16),
+    /// One of `ADD`, `SUB`, `MUL`, `IMUL`, `MULH` and `MULHU`.
+    Binary(usize, u16, u16),
+    /// One of `DIV`, `IDIV`, `REM` and `IREM` by a positive constant.
+    Divide(usize, u16, u16),
+    /// `DIVREM` or `IDIVREM` into the two registers by a positive constant.
+    DivRem(bool, u16, u16, u16),
+    /// One of `FADD`, `FSUB`, `FMUL`, `FDIV`, `FCMP`, `ITOF` and `FTOI`.
+    Float(usize, u16, u16),
+    /// One of `ADDO`, `IADDO`, `SUBO`, `ISUBO`, `MULO` and `IMULO`.
+    Checked(usize, u16, u16),
+    MemLoad(u16, u16),
+    MemStore(u16, u16),
+    Print(u16),
+    PrintFloat(u16),
+    Halt,
+    /// Skips the given number of following units, if the register is zero, nonzero or always.
+    Skip(Option<bool>, u16, usize),
+    /// Skips the given number of following units if one of `BEQ`, `BNE`, `BLT`, `BGE`, `BLTU`
+    /// and `BGEU` holds for the registers.
+    Branch(usize, u16, u16, usize),
+    /// `SWITCH` on the register, each case skipping the given number of following units.
+    Switch(u16, Vec<usize>),
+    Loop(u16, Vec<Unit>),
+    /// Calls the function that many indices above the current one.
+    Call(usize),
+}
+
+fn dst() -> impl Strategy<Value = u16> {
+    0..COUNTER
+}
+
+fn src() -> impl Strategy<Value = u16> {
+    0..8u16
+}
+
+fn simple() -> impl Strategy<Value = Unit> {
+    prop_oneof![
+        (dst(), 0..512u16).prop_map(|(dst, value)| Unit::Load(dst, value)),
+        (dst(), -256..256i16).prop_map(|(dst, value)| Unit::ILoad(dst, value)),
+        (dst(), src()).prop_map(|(dst, src)| Unit::Move(dst, src)),
+        (0..6usize, dst(), src()).prop_map(|(op, dst, src)| Unit::Binary(op, dst, src)),
+        (0..4usize, dst(), 1..256u16).prop_map(|(op, dst, value)| Unit::Divide(op, dst, value)),
+        (any::<bool>(), dst(), dst(), 1..256u16)
+            .prop_map(|(signed, dst, rem, value)| Unit::DivRem(signed, dst, rem, value)),
+        (0..7usize, dst(), src()).prop_map(|(op, dst, src)| Unit::Float(op, dst, src)),
+        (0..6usize, dst(), src()).prop_map(|(op, dst, src)| Unit::Checked(op, dst, src)),
+        (dst(), src()).prop_map(|(dst, src)| Unit::MemLoad(dst, src)),
+        (src(), src()).prop_map(|(dst, src)| Unit::MemStore(dst, src)),
+        src().prop_map(Unit::Print),
+        src().prop_map(Unit::PrintFloat),
+    ]
+}
+
+fn unit() -> impl Strategy<Value = Unit> {
+    prop_oneof![
+        8 => simple(),
+        1 => Just(Unit::Halt),
+        2 => (prop_oneof![Just(None), Just(Some(true)), Just(Some(false))], src(), 1..4usize)
+            .prop_map(|(cond, reg, len)| Unit::Skip(cond, reg, len)),
+        2 => (0..6usize, src(), src(), 1..8usize)
+            .prop_map(|(op, a, b, len)| Unit::Branch(op, a, b, len)),
+        2 => (src(), vec(1..4usize, 0..5)).prop_map(|(reg, cases)| Unit::Switch(reg, cases)),
+        2 => (1..6u16, vec(simple(), 1..6)).prop_map(|(count, body)| Unit::Loop(count, body)),
+        2 => (1..3usize).prop_map(Unit::Call),
+    ]
+}
+
+fn program() -> impl Strategy<Value = Vec<Vec<Unit>>> {
+    vec(vec(unit(), 0..16), 1..4)
+}
+
+/// Lowers the units of function `index` out of `count` to bytecode.
+fn assemble(units: &[Unit], index: usize, count: usize) -> Vec<u16> {
+    let mut code = Builder::default();
+    // Skips target the start of a unit or the final `RETURN`.
+    let starts = (0..=units.len()).map(|_| code.label()).collect::<Vec<_>>();
+    for (at, unit) in units.iter().enumerate() {
+        code.bind(starts[at]);
+        match *unit {
+            Unit::Skip(cond, reg, len) => {
+                let insn = match cond {
+                    None => __jump(0),
+                    Some(true) => __jumpz(reg, 0),
+                    Some(false) => __jumpnz(reg, 0),
+                };
+                code.jump(insn, starts[(at + len).min(units.len())]);
+            }
+            Unit::Branch(op, a, b, len) => {
+                let [insn, _] = [__beq, __bne, __blt, __bge, __bltu, __bgeu][op](a, b, 0);
+                code.jump(insn, starts[(at + len).min(units.len())]);
+            }
+            Unit::Switch(reg, ref cases) => {
+                let labels = cases
+                    .iter()
+                    .map(|len| starts[(at + len).min(units.len())])
+                    .collect::<Vec<_>>();
+                code.switch(reg, &labels);
+            }
+            Unit::Call(offset) => {
+                // Calls past the last function are dropped, keeping the call graph acyclic.
+                if index + offset < count {
+                    code.push(__call((index + offset) as u16));
+                }
+            }
+            Unit::Loop(count, ref body) => {
+                code.push(__load(COUNTER, count));
+                let start = code.label();
+                code.bind(start);
+                for unit in body {
+                    lower(unit, &mut code);
+                }
+                code.push(__load(SCRATCH, 1));
+                code.push(__sub(COUNTER, SCRATCH));
+                code.jump(__jumpnz(COUNTER, 0), start);
+            }
+            ref unit => lower(unit, &mut code),
+        }
+    }
+    code.bind(starts[units.len()]);
+    code.push(__return());
+    code.finish().unwrap()
+}
+
+fn lower(unit: &Unit, code: &mut Builder) {
+    match *unit {
+        Unit::Load(dst, value) => code.push(__load(dst, value)),
+        Unit::ILoad(dst, value) => code.push(__iload(dst, value)),
+        Unit::Move(dst, src) => code.push(__move(dst, src)),
+        Unit::Binary(op, dst, src) => {
+            code.push([__add, __sub, __mul, __imul, __mulh, __mulhu][op](dst, src))
+        }
+        Unit::Divide(op, dst, value) => {
+            code.push(__load(SCRATCH, value));
+            code.push([__div, __idiv, __rem, __irem][op](dst, SCRATCH));
+        }
+        Unit::DivRem(signed, dst, rem, value) => {
+            code.push(__load(SCRATCH, value));
+            code.push([__divrem, __idivrem][signed as usize](dst, rem, SCRATCH));
+        }
+        Unit::Float(op, dst, src) => {
+            code.push([__fadd, __fsub, __fmul, __fdiv, __fcmp, __itof, __ftoi][op](dst, src))
+        }
+        Unit::Checked(op, dst, src) => {
+            code.push([__addo, __iaddo, __subo, __isubo, __mulo, __imulo][op](
+                dst, src,
+            ))
+        }
+        Unit::MemLoad(dst, src) => code.push(__memload(dst, src)),
+        Unit::MemStore(dst, src) => code.push(__memstore(dst, src)),
+        Unit::Print(src) => code.push(__print(src)),
+        Unit::PrintFloat(src) => code.push(__printf(src)),
+        Unit::Halt => code.push(__halt()),
+        Unit::Skip(..) | Unit::Branch(..) | Unit::Switch(..) | Unit::Loop(..) | Unit::Call(_) => {
+            unreachable!("not a simple unit")
+        }
+    }
+}
+
+fn disassemble(funcs: &[Vec<u16>]) -> String {
+    let mut out = String::new();
+    for (index, code) in funcs.iter().enumerate() {
+        out += &format!("func{index}:\n");
+        for (pc, insn) in code.iter().enumerate() {
+            out += &format!("  {pc:3}: {insn:04x} {}\n", mnemonic(*insn));
+        }
+    }
+    out
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn test_interpreter_matches_jit(
+        program in program(),
+        mask in vec(any::<bool>(), 4),
+    ) {
+        let funcs = program
+            .iter()
+            .enumerate()
+            .map(|(index, units)| assemble(units, index, program.len()))
+            .collect::<Vec<_>>();
+        let expected = run(&funcs, [0; 8], &[], Tier::Interpreted);
+        for tier in [Tier::Compiled(&mask[..funcs.len()]), Tier::Speculated] {
+            let actual = run(&funcs, [0; 8], &[], tier);
+            prop_assert_eq!(
+                &actual,
+                &expected,
+                "{:?} diverged from the interpreter on\n{}",
+                tier,
+                disassemble(&funcs)
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+use crate::{
+    debugger::DebugHook,
+    ir::Frame,
+    runtime::{Context, Func},
+};
+
+/// Guest address of the first instruction of function 0. Function `n` starts at
+/// `CODE_BASE * (n + 1)`, and `mem` is mapped at 0.
+const CODE_BASE: u64 = 1 << 32;
+
+/// Size of `mem` in bytes.
+const MEM_SIZE: u64 = 1 << 16;
+
+/// Describes the registers of the `g` packet: `r0` to `r7`, then `pc`.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.jit-testing.core">
+    <reg name="r0" bitsize="64" type="int64" regnum="0"/>
+    <reg name="r1" bitsize="64" type="int64"/>
+    <reg name="r2" bitsize="64" type="int64"/>
+    <reg name="r3" bitsize="64" type="int64"/>
+    <reg name="r4" bitsize="64" type="int64"/>
+    <reg name="r5" bitsize="64" type="int64"/>
+    <reg name="r6" bitsize="64" type="int64"/>
+    <reg name="r7" bitsize="64" type="int64"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Debugs guest bytecode with a GDB remote serial protocol client, enabled by setting
+/// `Context::debugger`.
+///
+/// The client sees the guest registers and `pc`, `mem` at address 0 and the bytecode of each
+/// function in a separate 4 GiB region, see [`CODE_BASE`]. Breakpoints, watchpoints and
+/// single-stepping are implemented on the interpreter like those of [`crate::debugger::Debugger`].
+/// Execution is stopped before the first instruction.
+pub struct GdbStub {
+    input: BufReader<Box<dyn Read>>,
+    output: Box<dyn Write>,
+    /// Packets are acknowledged until the client requests `QStartNoAckMode`.
+    ack: bool,
+    stepping: bool,
+    /// The client is waiting for a stop reply.
+    running: bool,
+    breakpoints: BTreeSet<(usize, usize)>,
+    /// Watched ranges of `mem` and their contents last seen.
+    watchpoints: BTreeMap<(u16, u16), Vec<u8>>,
+}
+
+impl GdbStub {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self {
+            input: BufReader::new(input),
+            output,
+            ack: true,
+            stepping: true,
+            running: false,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Waits for a client to connect to `addr`.
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+
+    /// Serves packets until the client resumes execution. Returns `false` if it kills the
+    /// program.
+    fn serve(&mut self, ctx: &mut Context, stop: &str) -> io::Result<bool> {
+        if self.running {
+            self.send(stop)?;
+            self.running = false;
+        }
+        loop {
+            let Some(packet) = self.receive()? else {
+                // Detached by closing the connection.
+                self.detach();
+                return Ok(true);
+            };
+            let reply = match packet.as_str() {
+                "?" => stop.to_string(),
+                "c" | "s" => {
+                    self.stepping = packet == "s";
+                    self.running = true;
+                    return Ok(true);
+                }
+                "D" => {
+                    self.send("OK")?;
+                    self.detach();
+                    return Ok(true);
+                }
+                "k" | "vKill;1" => return Ok(false),
+                "g" => {
+                    let mut regs = String::new();
+                    for reg in &ctx.regs {
+                        hex(&mut regs, &unsafe { reg.uint }.to_le_bytes());
+                    }
+                    hex(&mut regs, &pc(ctx).to_le_bytes());
+                    regs
+                }
+                "QStartNoAckMode" => {
+                    self.send("OK")?;
+                    self.ack = false;
+                    continue;
+                }
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ if packet.starts_with('H') => "OK".to_string(),
+                _ if packet.starts_with("qSupported") => {
+                    "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+".to_string()
+                }
+                _ => self
+                    .command(ctx, &packet)
+                    .unwrap_or_else(|| "E01".to_string()),
+            };
+            self.send(&reply)?;
+        }
+    }
+
+    /// Handles packets with arguments, returning `None` if they are malformed.
+    fn command(&mut self, ctx: &mut Context, packet: &str) -> Option<String> {
+        let (kind, args) = packet.split_at_checked(1)?;
+        match kind {
+            "G" => {
+                let bytes = unhex(args)?;
+                if bytes.len() != 9 * 8 {
+                    return None;
+                }
+                let mut values = bytes
+                    .chunks(8)
+                    .map(|value| u64::from_le_bytes(value.try_into().unwrap()));
+                for reg in &mut ctx.regs {
+                    reg.uint = values.next()?;
+                }
+                set_pc(ctx, values.next()?)?;
+                Some("OK".to_string())
+            }
+            "p" => {
+                let mut reply = String::new();
+                match u64::from_str_radix(args, 16).ok()? {
+                    reg @ 0..=7 => hex(
+                        &mut reply,
+                        &unsafe { ctx.regs[reg as usize].uint }.to_le_bytes(),
+                    ),
+                    8 => hex(&mut reply, &pc(ctx).to_le_bytes()),
+                    _ => return None,
+                }
+                Some(reply)
+            }
+            "P" => {
+                let (reg, value) = args.split_once('=')?;
+                let value = u64::from_le_bytes(unhex(value)?.try_into().ok()?);
+                match usize::from_str_radix(reg, 16).ok()? {
+                    reg @ 0..=7 => ctx.regs[reg].uint = value,
+                    8 => set_pc(ctx, value)?,
+                    _ => return None,
+                }
+                Some("OK".to_string())
+            }
+            "m" => {
+                let (addr, len) = args.split_once(',')?;
+                let addr = u64::from_str_radix(addr, 16).ok()?;
+                let len = u64::from_str_radix(len, 16).ok()?;
+                let mut reply = String::new();
+                hex(&mut reply, &read(ctx, addr, len)?);
+                Some(reply)
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (addr, len) = range.split_once(',')?;
+                let addr = u64::from_str_radix(addr, 16).ok()?;
+                let data = unhex(data)?;
+                if u64::from_str_radix(len, 16).ok()? != data.len() as u64 {
+                    return None;
+                }
+                addr.checked_add(data.len() as u64)
+                    .filter(|&end| end <= MEM_SIZE)?;
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        data.as_ptr(),
+                        ctx.mem.add(addr as usize),
+                        data.len(),
+                    )
+                };
+                Some("OK".to_string())
+            }
+            "Z" | "z" => {
+                let mut args = args.split(',');
+                let (kind, addr, len) = (args.next()?, args.next()?, args.next()?);
+                let addr = u64::from_str_radix(addr, 16).ok()?;
+                let len = u64::from_str_radix(len, 16).ok()?;
+                let insert = packet.starts_with('Z');
+                match kind {
+                    "0" | "1" => {
+                        let frame = locate(ctx, addr)?;
+                        if !insert {
+                            self.breakpoints.remove(&(frame.func, frame.pc));
+                        } else if ctx.deoptimize_all().is_ok() {
+                            self.breakpoints.insert((frame.func, frame.pc));
+                        } else {
+                            return None;
+                        }
+                    }
+                    "2" => {
+                        addr.checked_add(len).filter(|&end| end <= MEM_SIZE)?;
+                        let range = (addr as u16, u16::try_from(len).ok().filter(|&len| len > 0)?);
+                        if insert {
+                            self.watchpoints.insert(range, read(ctx, addr, len)?);
+                        } else {
+                            self.watchpoints.remove(&range);
+                        }
+                    }
+                    // Read and access watchpoints are not supported.
+                    _ => return Some(String::new()),
+                }
+                Some("OK".to_string())
+            }
+            "q" => {
+                let args = args.strip_prefix("Xfer:features:read:target.xml:")?;
+                let (offset, len) = args.split_once(',')?;
+                let offset = usize::from_str_radix(offset, 16)
+                    .ok()?
+                    .min(TARGET_XML.len());
+                let len = usize::from_str_radix(len, 16).ok()?;
+                let chunk = &TARGET_XML[offset..TARGET_XML.len().min(offset.saturating_add(len))];
+                let more = offset + chunk.len() < TARGET_XML.len();
+                Some(format!("{}{chunk}", if more { 'm' } else { 'l' }))
+            }
+            // Unsupported packets get an empty reply.
+            _ => Some(String::new()),
+        }
+    }
+
+    /// Stops debugging, letting the program run to completion.
+    fn detach(&mut self) {
+        self.stepping = false;
+        self.running = false;
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Reads the next packet, skipping acknowledgements and interrupts. Returns `None` once
+    /// the connection is closed.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            loop {
+                if self.input.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                if self.input.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.input.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(checksum_of(&data));
+            if self.ack {
+                self.output.write_all(if valid { b"+" } else { b"-" })?;
+                self.output.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        self.output.write_all(packet.as_bytes())?;
+        self.output.flush()?;
+        if self.ack {
+            // Retransmit until the client acknowledges the packet.
+            let mut byte = [0];
+            loop {
+                if self.input.read(&mut byte)? == 0 || byte[0] == b'+' {
+                    return Ok(());
+                }
+                if byte[0] == b'-' {
+                    self.output.write_all(packet.as_bytes())?;
+                    self.output.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DebugHook for GdbStub {
+    fn before_step(&mut self, ctx: &mut Context) -> bool {
+        let mut stop = self.stepping.then(|| "S05".to_string());
+        if let Some(frame) = Func::locate(&ctx.funcs, ctx.pc) {
+            if self.breakpoints.contains(&(frame.func, frame.pc)) {
+                stop = Some("T05swbreak:;".to_string());
+            }
+        }
+        for (&(addr, len), old) in &mut self.watchpoints {
+            let new = read(ctx, addr as u64, len as u64).unwrap();
+            if new != *old {
+                stop = Some(format!("T05watch:{addr:x};"));
+                *old = new;
+            }
+        }
+        let Some(stop) = stop else {
+            return true;
+        };
+        self.serve(ctx, &stop).unwrap_or(false)
+    }
+}
+
+impl Drop for GdbStub {
+    /// Tells a waiting client that the program exited.
+    fn drop(&mut self) {
+        if self.running {
+            self.send("W00").ok();
+        }
+    }
+}
+
+fn pc(ctx: &Context) -> u64 {
+    Func::locate(&ctx.funcs, ctx.pc).map_or(0, |frame| {
+        CODE_BASE * (frame.func as u64 + 1) + frame.pc as u64 * 2
+    })
+}
+
+fn set_pc(ctx: &mut Context, pc: u64) -> Option<()> {
+    let frame = locate(ctx, pc)?;
+    ctx.pc = ctx.funcs[frame.func].code[frame.pc..].as_ptr();
+    Some(())
+}
+
+/// Returns the instruction at the guest address `addr`.
+fn locate(ctx: &Context, addr: u64) -> Option<Frame> {
+    let func = (addr / CODE_BASE).checked_sub(1)? as usize;
+    let pc = (addr % CODE_BASE / 2) as usize;
+    (addr.is_multiple_of(2) && pc < ctx.funcs.get(func)?.code.len()).then_some(Frame { func, pc })
+}
+
+/// Reads `len` bytes of `mem` or bytecode at the guest address `addr`.
+fn read(ctx: &Context, addr: u64, len: u64) -> Option<Vec<u8>> {
+    let end = addr.checked_add(len)?;
+    if end <= MEM_SIZE {
+        let mem = unsafe { std::slice::from_raw_parts(ctx.mem, MEM_SIZE as usize) };
+        return Some(mem[addr as usize..end as usize].to_vec());
+    }
+    let func = ctx.funcs.get((addr / CODE_BASE).checked_sub(1)? as usize)?;
+    let code =
+        unsafe { std::slice::from_raw_parts(func.code.as_ptr() as *const u8, func.code.len() * 2) };
+    let start = (addr % CODE_BASE) as usize;
+    code.get(start..start.checked_add(len as usize)?)
+        .map(<[u8]>::to_vec)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(out, "{byte:02x}").unwrap();
+    }
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{Cursor, Write},
+        rc::Rc,
+    };
+
+    use super::{checksum_of, GdbStub, TARGET_XML};
+    use crate::{
+        opcodes::{__call, __iload, __load, __memstore, __return},
+        runtime::{Context, Func, Runner},
+    };
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+    }
+
+    #[test]
+    fn test_gdbstub_session() {
+        let mut ctx = Context::default();
+        let mut runner = Runner::default();
+        ctx.funcs.push(Func::new(vec![__call(1), __return()]));
+        ctx.funcs.push(Func::new(vec![
+            __load(0, 8),
+            __iload(1, 5),
+            __memstore(0, 1),
+            __return(),
+        ]));
+        ctx.compile(1).unwrap();
+        ctx.pc = ctx.funcs[0].addr.address as *const u16;
+        unsafe { (ctx.mem.add(8) as *mut u64).write_unaligned(0) };
+        // Stop at the store, step over it and check memory and registers, then detach.
+        let input = [
+            "+",
+            &packet("QStartNoAckMode"),
+            "+",
+            &packet("?"),
+            &packet("Z0,200000004,2"),
+            &packet("c"),
+            &packet("p8"),
+            &packet("s"),
+            &packet("m8,2"),
+            &packet("P1=0700000000000000"),
+            &packet("p1"),
+            &packet("m200000006,2"),
+            &packet("D"),
+        ]
+        .concat();
+        let output = Output::default();
+        ctx.debugger = Some(Box::new(GdbStub::new(
+            Box::new(Cursor::new(input)),
+            Box::new(output.clone()),
+        )));
+        runner.run(&mut ctx);
+        let output = String::from_utf8(output.0.take()).unwrap();
+        let expected = [
+            "+".to_string(),
+            packet("OK"),
+            packet("S05"),
+            packet("OK"),
+            packet("T05swbreak:;"),
+            packet("0400000002000000"),
+            packet("S05"),
+            packet("0500"),
+            packet("OK"),
+            packet("0700000000000000"),
+            packet("0004"),
+            packet("OK"),
+        ]
+        .concat();
+        assert_eq!(output, expected);
+        assert!(ctx.code.get(1).is_none());
+        assert_eq!(unsafe { ctx.regs[1].int }, 7);
+    }
+    #[test]
+    fn test_gdbstub_malformed_packets() {
+        let mut ctx = Context::default();
+        let mut runner = Runner::default();
+        ctx.funcs.push(Func::new(vec![__return()]));
+        ctx.pc = ctx.funcs[0].code.as_ptr();
+        let input = [
+            "+",
+            &packet("QStartNoAckMode"),
+            "+",
+            &packet(""),
+            &packet("\u{e9}"),
+            &packet("Mffffffffffffffff,1:00"),
+            &packet("Mffff,2:0000"),
+            &packet("Z2,0,0"),
+            &packet("Z2,0,10000"),
+            &packet("qXfer:features:read:target.xml:1,ffffffffffffffff"),
+            &packet("D"),
+        ]
+        .concat();
+        let output = Output::default();
+        ctx.debugger = Some(Box::new(GdbStub::new(
+            Box::new(Cursor::new(input)),
+            Box::new(output.clone()),
+        )));
+        runner.run(&mut ctx);
+        let output = String::from_utf8(output.0.take()).unwrap();
+        let expected = [
+            "+".to_string(),
+            packet("OK"),
+            packet("E01"),
+            packet("E01"),
+            packet("E01"),
+            packet("E01"),
+            packet("E01"),
+            packet("E01"),
+            packet(&format!("l{}", &TARGET_XML[1..])),
+            packet("OK"),
+        ]
+        .concat();
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,112 @@
+//! Runs programs on every engine for the conformance and fuzz tests.
+
+use std::fmt;
+
+use crate::{
+    feedback::Feedback,
+    opcodes::{__call, __return},
+    runtime::{Context, Func, Runner, Trap},
+};
+
+/// Size of `Context::mem` in bytes.
+pub const MEM_SIZE: usize = u16::MAX as usize + 8;
+
+/// Observable state after running a program.
+#[derive(PartialEq, Eq)]
+pub struct Outcome {
+    pub regs: [i64; 8],
+    pub mem: Vec<u8>,
+    pub printed: Vec<String>,
+    pub trapped: Option<Trap>,
+    /// Functions the JIT rejected, which stayed interpreted.
+    pub rejected: Vec<usize>,
+}
+
+impl fmt::Debug for Outcome {
+    /// Prints `mem` as a hash to keep failure reports short.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hash = self.mem.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        f.debug_struct("Outcome")
+            .field("regs", &self.regs)
+            .field("mem", &format_args!("{hash:016x}"))
+            .field("printed", &self.printed)
+            .field("trapped", &self.trapped)
+            .field("rejected", &self.rejected)
+            .finish()
+    }
+}
+
+/// How the functions of a program are run.
+#[derive(Clone, Copy, Debug)]
+pub enum Tier<'a> {
+    Interpreted,
+    /// The interpreter executing one instruction at a time, as it does while collecting
+    /// feedback.
+    Stepping,
+    /// Compiles the functions selected by the mask.
+    Compiled(&'a [bool]),
+    /// Runs the program interpreted to collect feedback, then compiles all functions with it.
+    Speculated,
+}
+
+/// Runs `funcs[0]` from the given registers and 64-bit values in otherwise zeroed `mem`.
+pub fn run(funcs: &[Vec<u16>], regs: [i64; 8], mem: &[(u16, i64)], tier: Tier) -> Outcome {
+    let mut ctx = Context::default();
+    for code in funcs {
+        ctx.funcs.push(Func::new(code.clone()));
+    }
+    // Entering through an interpreted driver exercises the transition into compiled code.
+    ctx.funcs.push(Func::new(vec![__call(0), __return()]));
+    let mut rejected = Vec::new();
+    let mut compile = |ctx: &mut Context, index| {
+        if ctx.compile(index).is_err() {
+            rejected.push(index);
+        }
+    };
+    match tier {
+        Tier::Interpreted => {}
+        Tier::Stepping => ctx.feedback = Some(Feedback::default()),
+        Tier::Compiled(mask) => {
+            for (index, _) in mask.iter().enumerate().filter(|(_, compile)| **compile) {
+                compile(&mut ctx, index);
+            }
+        }
+        Tier::Speculated => {
+            ctx.feedback = Some(Feedback::default());
+            for _ in 0..Feedback::MIN_COUNT {
+                execute(&mut ctx, regs, mem);
+            }
+            for index in 0..funcs.len() {
+                compile(&mut ctx, index);
+            }
+            ctx.feedback = None;
+        }
+    }
+    execute(&mut ctx, regs, mem);
+    Outcome {
+        regs: ctx.regs.map(|reg| unsafe { reg.int }),
+        mem: unsafe { std::slice::from_raw_parts(ctx.mem, MEM_SIZE) }.to_vec(),
+        printed: ctx.printed.take().unwrap(),
+        trapped: ctx.trapped,
+        rejected,
+    }
+}
+
+/// Runs the driver calling `funcs[0]` from the initial state.
+fn execute(ctx: &mut Context, regs: [i64; 8], mem: &[(u16, i64)]) {
+    let mut runner = Runner::default();
+    unsafe { std::ptr::write_bytes(ctx.mem, 0, MEM_SIZE) };
+    for &(addr, value) in mem {
+        unsafe { (ctx.mem.add(addr as usize) as *mut i64).write_unaligned(value) };
+    }
+    for (reg, value) in ctx.regs.iter_mut().zip(regs) {
+        reg.int = value;
+    }
+    ctx.callstack.set_entries(&[]);
+    ctx.trapped = None;
+    ctx.printed = Some(Vec::new());
+    ctx.pc = ctx.funcs.last().unwrap().code.as_ptr();
+    runner.run(ctx);
+}
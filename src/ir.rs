@@ -0,0 +1,848 @@
+use anyhow::anyhow;
+
+use crate::{
+    opcodes::{
+        ADD, ADDO, BEQ, BGE, BGEU, BLT, BLTU, BNE, BRANCH, CALL, CHECKEDOP, DIV, DIVREM, FADD,
+        FCMP, FDIV, FLOATOP, FMUL, FSUB, FTOI, HALT, IADDO, IDIV, IDIVREM, ILOAD, IMUL, IMULO,
+        IREM, ISUBO, ITOF, JUMP, JUMPNZ, JUMPZ, LJUMP, LOAD, MEMLOAD, MEMSTORE, MOVE, MUL, MULH,
+        MULHU, MULO, NOOP, PRINT, PRINTF, REM, RETURN, SMALLOP, SUB, SUBO, SWITCH, WIDEOP,
+    },
+    runtime::{canonical_nan, compare_floats, long_offset, sign_extend},
+};
+
+/// Arithmetic performed by [`Insn::Binary`].
+///
+/// `MUL` and `IMUL` produce the same low 64 bits and are both represented by `Mul`. The float
+/// operations work on the registers' bits as `f64`. The checked operations trap instead of
+/// wrapping around, leaving the destination unchanged. `MulH` and `MulHU` are the high 64 bits
+/// of the signed and unsigned 128-bit product.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Rem,
+    IRem,
+    Shl,
+    Shr,
+    And,
+    FAdd,
+    FSub,
+    FMul,
+    FDiv,
+    FCmp,
+    CheckedAdd,
+    CheckedIAdd,
+    CheckedSub,
+    CheckedISub,
+    CheckedMul,
+    CheckedIMul,
+    MulH,
+    MulHU,
+}
+
+impl BinOp {
+    /// Evaluates the operation, returning `None` if it would trap.
+    pub fn eval(self, a: i64, b: i64) -> Option<i64> {
+        let (ua, ub) = (a as u64, b as u64);
+        Some(match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::Div => ua.checked_div(ub)? as i64,
+            BinOp::IDiv => a.checked_div(b)?,
+            BinOp::Rem => ua.checked_rem(ub)? as i64,
+            BinOp::IRem => a.checked_rem(b)?,
+            BinOp::Shl => ua.checked_shl(b.try_into().ok()?)? as i64,
+            BinOp::Shr => ua.checked_shr(b.try_into().ok()?)? as i64,
+            BinOp::And => a & b,
+            BinOp::FAdd => canonical_nan(float(a) + float(b)).to_bits() as i64,
+            BinOp::FSub => canonical_nan(float(a) - float(b)).to_bits() as i64,
+            BinOp::FMul => canonical_nan(float(a) * float(b)).to_bits() as i64,
+            BinOp::FDiv => canonical_nan(float(a) / float(b)).to_bits() as i64,
+            BinOp::FCmp => compare_floats(float(a), float(b)),
+            BinOp::CheckedAdd => ua.checked_add(ub)? as i64,
+            BinOp::CheckedIAdd => a.checked_add(b)?,
+            BinOp::CheckedSub => ua.checked_sub(ub)? as i64,
+            BinOp::CheckedISub => a.checked_sub(b)?,
+            BinOp::CheckedMul => ua.checked_mul(ub)? as i64,
+            BinOp::CheckedIMul => a.checked_mul(b)?,
+            BinOp::MulH => ((a as i128 * b as i128) >> 64) as i64,
+            BinOp::MulHU => ((ua as u128 * ub as u128) >> 64) as i64,
+        })
+    }
+
+    /// Whether the operation can trap at runtime.
+    pub fn can_trap(self) -> bool {
+        matches!(self, BinOp::Div | BinOp::IDiv | BinOp::Rem | BinOp::IRem) || self.is_checked()
+    }
+
+    /// Whether the operation traps on overflow.
+    pub fn is_checked(self) -> bool {
+        matches!(
+            self,
+            BinOp::CheckedAdd
+                | BinOp::CheckedIAdd
+                | BinOp::CheckedSub
+                | BinOp::CheckedISub
+                | BinOp::CheckedMul
+                | BinOp::CheckedIMul
+        )
+    }
+}
+
+fn float(bits: i64) -> f64 {
+    f64::from_bits(bits as u64)
+}
+
+/// Conversion performed by [`Insn::Unary`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    /// `ITOF`, rounding to the nearest double.
+    IntToFloat,
+    /// `FTOI`, truncating and saturating like `as i64`.
+    FloatToInt,
+}
+
+impl UnOp {
+    pub fn eval(self, a: i64) -> i64 {
+        match self {
+            UnOp::IntToFloat => (a as f64).to_bits() as i64,
+            UnOp::FloatToInt => float(a) as i64,
+        }
+    }
+}
+
+/// Comparison performed by [`Insn::Branch`]. `LtU` and `GeU` compare unsigned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    LtU,
+    GeU,
+}
+
+impl Cond {
+    /// Returns the condition of a `BEQ`, `BNE`, `BLT`, `BGE`, `BLTU` or `BGEU` instruction, or
+    /// `None` if the `BRANCH` is invalid.
+    pub fn of(insn: u16) -> Option<Self> {
+        Some(match insn & 0xffc0 {
+            BEQ => Cond::Eq,
+            BNE => Cond::Ne,
+            BLT => Cond::Lt,
+            BGE => Cond::Ge,
+            BLTU => Cond::LtU,
+            BGEU => Cond::GeU,
+            _ => return None,
+        })
+    }
+
+    pub fn holds(self, a: i64, b: i64) -> bool {
+        match self {
+            Cond::Eq => a == b,
+            Cond::Ne => a != b,
+            Cond::Lt => a < b,
+            Cond::Ge => a >= b,
+            Cond::LtU => (a as u64) < b as u64,
+            Cond::GeU => a as u64 >= b as u64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Imm(i64),
+}
+
+/// Condition a [`Insn::Guard`] checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    Eq(i64),
+}
+
+impl Check {
+    pub fn passes(self, value: i64) -> bool {
+        match self {
+            Check::Eq(expected) => value == expected,
+        }
+    }
+
+    /// The value a register holds once the check passed, if it is known.
+    pub fn value(self) -> Option<i64> {
+        match self {
+            Check::Eq(expected) => Some(expected),
+        }
+    }
+}
+
+/// `dst op= value`, executed by a [`Insn::Guard`] once its check passed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Speculated {
+    pub op: BinOp,
+    pub dst: u8,
+    pub value: i64,
+}
+
+/// A decoded instruction.
+///
+/// Functions are decoded into one `Insn` per bytecode word, so indices and jump targets are
+/// bytecode offsets. Passes that delete instructions replace them with [`Insn::Nop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Insn {
+    Nop,
+    Move {
+        dst: u8,
+        src: u8,
+    },
+    Const {
+        dst: u8,
+        value: i64,
+    },
+    Binary {
+        op: BinOp,
+        dst: u8,
+        src: Operand,
+    },
+    Unary {
+        op: UnOp,
+        dst: u8,
+        src: u8,
+    },
+    /// `DIVREM` and `IDIVREM`, writing the quotient to `dst` and then the remainder to `rem`.
+    DivRem {
+        signed: bool,
+        dst: u8,
+        rem: u8,
+        src: u8,
+    },
+    MemLoad {
+        dst: u8,
+        src: u8,
+    },
+    MemStore {
+        dst: u8,
+        src: u8,
+    },
+    /// `PRINT`, or `PRINTF` if `float` is set.
+    Print {
+        src: u8,
+        float: bool,
+    },
+    Halt,
+    Return,
+    Jump {
+        target: usize,
+    },
+    JumpZ {
+        cond: u8,
+        target: usize,
+    },
+    JumpNz {
+        cond: u8,
+        target: usize,
+    },
+    /// `BEQ`, `BNE`, `BLT`, `BGE`, `BLTU` and `BGEU`, jumping if `cond` holds for `a` and `b`.
+    Branch {
+        cond: Cond,
+        a: u8,
+        b: u8,
+        target: usize,
+    },
+    /// `SWITCH`, continuing at the target of the `reg`th of the `len` [`Insn::Case`]s following
+    /// it, or behind them if `reg` is out of range.
+    Switch {
+        reg: u8,
+        len: usize,
+    },
+    /// An entry of a `SWITCH` table. It is never executed, but control flow treats it like a
+    /// conditional jump, so the cases and the default behind the last one all follow the
+    /// `SWITCH`.
+    Case {
+        target: usize,
+    },
+    Call {
+        func: usize,
+    },
+    /// Leaves compiled code for the interpreter, which executes the instruction the guard
+    /// replaced, unless `reg` passes `check`. Runs `then` otherwise.
+    Guard {
+        reg: u8,
+        check: Check,
+        then: Option<Speculated>,
+    },
+}
+
+impl Insn {
+    /// Registers explicitly read by the instruction.
+    pub fn reads(&self) -> u8 {
+        match *self {
+            Insn::Move { src, .. }
+            | Insn::Unary { src, .. }
+            | Insn::MemLoad { src, .. }
+            | Insn::Print { src, .. } => 1 << src,
+            Insn::Binary { dst, src, .. } => match src {
+                Operand::Reg(src) => 1 << dst | 1 << src,
+                Operand::Imm(_) => 1 << dst,
+            },
+            Insn::MemStore { dst, src } | Insn::DivRem { dst, src, .. } => 1 << dst | 1 << src,
+            Insn::Branch { a, b, .. } => 1 << a | 1 << b,
+            Insn::JumpZ { cond, .. } | Insn::JumpNz { cond, .. } => 1 << cond,
+            Insn::Switch { reg, .. } => 1 << reg,
+            Insn::Guard { reg, then, .. } => 1 << reg | then.map_or(0, |then| 1 << then.dst),
+            _ => 0,
+        }
+    }
+
+    /// Registers explicitly written by the instruction.
+    pub fn writes(&self) -> u8 {
+        match *self {
+            Insn::Move { dst, .. }
+            | Insn::Const { dst, .. }
+            | Insn::Binary { dst, .. }
+            | Insn::Unary { dst, .. }
+            | Insn::MemLoad { dst, .. } => 1 << dst,
+            Insn::DivRem { dst, rem, .. } => 1 << dst | 1 << rem,
+            Insn::Guard {
+                then: Some(then), ..
+            } => 1 << then.dst,
+            _ => 0,
+        }
+    }
+
+    /// The instruction only writes its destination and can be removed if that is dead.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Insn::Move { .. } | Insn::Const { .. } | Insn::Unary { .. } | Insn::MemLoad { .. } => {
+                true
+            }
+            Insn::Binary { op, .. } => !op.can_trap(),
+            _ => false,
+        }
+    }
+
+    /// Jump target of the instruction, if any.
+    pub fn target(&self) -> Option<usize> {
+        match *self {
+            Insn::Jump { target }
+            | Insn::JumpZ { target, .. }
+            | Insn::JumpNz { target, .. }
+            | Insn::Branch { target, .. }
+            | Insn::Case { target } => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Replaces the jump target of the instruction, if it has one.
+    pub fn set_target(&mut self, new: usize) {
+        match self {
+            Insn::Jump { target }
+            | Insn::JumpZ { target, .. }
+            | Insn::JumpNz { target, .. }
+            | Insn::Branch { target, .. }
+            | Insn::Case { target } => *target = new,
+            _ => {}
+        }
+    }
+
+    /// Control never falls through to the next instruction.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Insn::Jump { .. } | Insn::Return | Insn::Halt)
+    }
+
+    /// Other code observes every register in `Context::regs` at this instruction.
+    ///
+    /// Checked operations and `DIVREM` hand over to the interpreter when they trap, like failed
+    /// guards.
+    pub fn observes_regs(&self) -> bool {
+        match self {
+            Insn::Call { .. }
+            | Insn::Return
+            | Insn::Halt
+            | Insn::Guard { .. }
+            | Insn::DivRem { .. } => true,
+            Insn::Binary { op, .. } => op.is_checked(),
+            _ => false,
+        }
+    }
+}
+
+/// A maximal straight-line run of instructions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Block {
+    /// Index of the first instruction.
+    pub start: usize,
+    /// Index one past the last instruction.
+    pub end: usize,
+    /// Indices of the blocks control can continue in.
+    pub succs: Vec<usize>,
+    /// Indices of the blocks control can come from.
+    pub preds: Vec<usize>,
+    /// The block can be reached from the function entry.
+    pub reachable: bool,
+    /// Registers read before they are written in the block.
+    pub uses: u8,
+    /// Registers written in the block.
+    pub defs: u8,
+    /// Registers whose value may be read after entering the block.
+    pub live_in: u8,
+    /// Registers whose value may be read after leaving the block.
+    pub live_out: u8,
+}
+
+/// A callee whose body was copied into the caller by [`crate::opt::inline`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Inlined {
+    /// Index of the callee in `Context::funcs`.
+    pub func: usize,
+    /// Index of the instruction that called the callee.
+    pub site: usize,
+    /// Index of the callee's first instruction.
+    pub start: usize,
+    /// Number of instructions of the callee.
+    pub len: usize,
+}
+
+/// A bytecode location the interpreter can resume at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Index of the function in `Context::funcs`.
+    pub func: usize,
+    /// Offset of the instruction in the function's bytecode.
+    pub pc: usize,
+}
+
+/// A decoded function together with its control flow graph and register liveness.
+///
+/// Both back ends lower from this representation, so analyses and optimizations only have to
+/// be written once. The first `code.len()` instructions correspond to the function's bytecode;
+/// inlined callees are appended after them.
+#[derive(Clone, Debug, Default)]
+pub struct Function {
+    pub insns: Vec<Insn>,
+    pub blocks: Vec<Block>,
+    pub inlined: Vec<Inlined>,
+}
+
+impl Function {
+    /// Decodes `code` and analyzes it. `funcs` is the number of functions `CALL` may refer to.
+    pub fn new(code: &[u16], funcs: usize) -> anyhow::Result<Self> {
+        Ok(Self::from_insns(decode(code, funcs)?))
+    }
+
+    pub fn from_insns(insns: Vec<Insn>) -> Self {
+        let mut res = Self {
+            insns,
+            blocks: Vec::with_capacity(0),
+            inlined: Vec::with_capacity(0),
+        };
+        res.analyze();
+        res
+    }
+
+    /// Rebuilds the blocks, edges and liveness after the instructions changed.
+    pub fn analyze(&mut self) {
+        let starts = block_starts(&self.insns);
+        self.blocks.clear();
+        for (i, start) in starts.iter().enumerate() {
+            if *start {
+                if let Some(last) = self.blocks.last_mut() {
+                    last.end = i;
+                }
+                self.blocks.push(Block {
+                    start: i,
+                    ..Default::default()
+                });
+            }
+        }
+        if let Some(last) = self.blocks.last_mut() {
+            last.end = self.insns.len();
+        }
+        for index in 0..self.blocks.len() {
+            let Block { start, end, .. } = self.blocks[index];
+            let last = self.insns[end - 1];
+            let mut succs = Vec::with_capacity(2);
+            if let Some(target) = last.target() {
+                succs.push(self.block_of(target));
+            }
+            if !last.is_terminator() && end < self.insns.len() {
+                succs.push(index + 1);
+            }
+            succs.dedup();
+            for succ in &succs {
+                self.blocks[*succ].preds.push(index);
+            }
+            let (mut uses, mut defs) = (0, 0);
+            for insn in &self.insns[start..end] {
+                uses |= insn.reads() & !defs;
+                if insn.observes_regs() {
+                    uses |= !defs;
+                }
+                defs |= insn.writes();
+            }
+            let block = &mut self.blocks[index];
+            block.succs = succs;
+            block.uses = uses;
+            block.defs = defs;
+        }
+        self.mark_reachable();
+        self.compute_liveness();
+    }
+
+    /// Returns where the interpreter resumes once the `CALL` at instruction `insn` returns,
+    /// innermost frame first.
+    ///
+    /// `func` is the index of this function. Every inlined callee around `insn` adds a frame
+    /// that returns behind its call site.
+    pub fn resume_frames(&self, func: usize, insn: usize) -> Vec<Frame> {
+        let mut frames = self.frames(func, insn);
+        frames[0].pc += 1;
+        frames
+    }
+
+    /// Returns the bytecode location of instruction `insn` followed by the locations inlined
+    /// callees around it return to, like [`Function::resume_frames`].
+    pub fn frames(&self, func: usize, mut insn: usize) -> Vec<Frame> {
+        let mut frames = Vec::with_capacity(1);
+        loop {
+            // Outer frames continue behind the call site.
+            let next = usize::from(!frames.is_empty());
+            let Some(inlined) = self
+                .inlined
+                .iter()
+                .find(|inlined| (inlined.start..inlined.start + inlined.len).contains(&insn))
+            else {
+                frames.push(Frame {
+                    func,
+                    pc: insn + next,
+                });
+                return frames;
+            };
+            frames.push(Frame {
+                func: inlined.func,
+                pc: insn - inlined.start + next,
+            });
+            insn = inlined.site;
+        }
+    }
+
+    /// Index of the block containing instruction `insn`.
+    pub fn block_of(&self, insn: usize) -> usize {
+        self.blocks.partition_point(|block| block.start <= insn) - 1
+    }
+
+    fn mark_reachable(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let mut work = vec![0];
+        while let Some(index) = work.pop() {
+            let block = &mut self.blocks[index];
+            if block.reachable {
+                continue;
+            }
+            block.reachable = true;
+            work.extend_from_slice(&block.succs);
+        }
+    }
+
+    fn compute_liveness(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..self.blocks.len()).rev() {
+                let block = &self.blocks[index];
+                let last = self.insns[block.end - 1];
+                // Falling off the end of the function leaves the registers to the caller.
+                let mut live_out = if block.succs.is_empty() && !last.observes_regs() {
+                    0xff
+                } else {
+                    0
+                };
+                for succ in &block.succs {
+                    live_out |= self.blocks[*succ].live_in;
+                }
+                let live_in = block.uses | (live_out & !block.defs);
+                let block = &mut self.blocks[index];
+                if block.live_in != live_in || block.live_out != live_out {
+                    block.live_in = live_in;
+                    block.live_out = live_out;
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Decodes and validates a function's bytecode.
+///
+/// `funcs` is the number of functions `CALL` may refer to. The word following an `LJUMP` or a
+/// branch becomes a [`Insn::Nop`] and the table of a `SWITCH` [`Insn::Case`]s, which no jump may
+/// target.
+pub fn decode(code: &[u16], funcs: usize) -> anyhow::Result<Vec<Insn>> {
+    let mut insns = Vec::with_capacity(code.len());
+    // The instruction each operand word belongs to.
+    let mut operands = vec![None; code.len()];
+    for (i, insn) in code.iter().enumerate() {
+        if let Some(owner) = operands[i] {
+            insns.push(if code[owner] & 0xf000 == SWITCH {
+                let target = owner as isize + code[i] as i16 as isize;
+                if target < 0 || target >= code.len() as isize {
+                    return Err(anyhow!("Invalid switch: 0x{:04x}", code[owner]));
+                }
+                Insn::Case {
+                    target: target as usize,
+                }
+            } else {
+                Insn::Nop
+            });
+            continue;
+        }
+        let insn = *insn;
+        let a = (insn & 0x7) as u8;
+        let b = ((insn & 0x38) >> 3) as u8;
+        let jump = |offset: i64| {
+            let target = i as isize + offset as isize;
+            if target < 0 || target >= code.len() as isize {
+                return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
+            }
+            Ok(target as usize)
+        };
+        let binary = |op| Insn::Binary {
+            op,
+            dst: a,
+            src: Operand::Reg(b),
+        };
+        let opc = insn & 0xf000;
+        insns.push(match opc {
+            SMALLOP => {
+                let op = insn & 0xf00;
+                match op {
+                    NOOP => Insn::Nop,
+                    MOVE => Insn::Move { dst: a, src: b },
+                    MEMLOAD => Insn::MemLoad { dst: a, src: b },
+                    MEMSTORE => Insn::MemStore { dst: a, src: b },
+                    RETURN => Insn::Return,
+                    ADD => binary(BinOp::Add),
+                    SUB => binary(BinOp::Sub),
+                    MUL | IMUL => binary(BinOp::Mul),
+                    DIV => binary(BinOp::Div),
+                    IDIV => binary(BinOp::IDiv),
+                    REM => binary(BinOp::Rem),
+                    IREM => binary(BinOp::IRem),
+                    PRINT => Insn::Print {
+                        src: a,
+                        float: false,
+                    },
+                    HALT => Insn::Halt,
+                    _ => return Err(anyhow!("Invalid small instruction: 0x{insn:04x}")),
+                }
+            }
+            LOAD => Insn::Const {
+                dst: a,
+                value: ((insn & 0xff8) >> 3) as i64,
+            },
+            ILOAD => Insn::Const {
+                dst: a,
+                value: sign_extend::<9>((insn & 0xff8) >> 3),
+            },
+            FLOATOP => match insn & 0xff00 {
+                FADD => binary(BinOp::FAdd),
+                FSUB => binary(BinOp::FSub),
+                FMUL => binary(BinOp::FMul),
+                FDIV => binary(BinOp::FDiv),
+                FCMP => binary(BinOp::FCmp),
+                ITOF => Insn::Unary {
+                    op: UnOp::IntToFloat,
+                    dst: a,
+                    src: b,
+                },
+                FTOI => Insn::Unary {
+                    op: UnOp::FloatToInt,
+                    dst: a,
+                    src: b,
+                },
+                PRINTF => Insn::Print {
+                    src: a,
+                    float: true,
+                },
+                _ => return Err(anyhow!("Invalid float instruction: 0x{insn:04x}")),
+            },
+            WIDEOP => {
+                let c = ((insn & 0x1c0) >> 6) as u8;
+                match insn & 0xfe00 {
+                    MULH => binary(BinOp::MulH),
+                    MULHU => binary(BinOp::MulHU),
+                    DIVREM | IDIVREM => Insn::DivRem {
+                        signed: insn & 0xfe00 == IDIVREM,
+                        dst: a,
+                        rem: c,
+                        src: b,
+                    },
+                    _ => return Err(anyhow!("Invalid wide instruction: 0x{insn:04x}")),
+                }
+            }
+            CHECKEDOP => match insn & 0xff00 {
+                ADDO => binary(BinOp::CheckedAdd),
+                IADDO => binary(BinOp::CheckedIAdd),
+                SUBO => binary(BinOp::CheckedSub),
+                ISUBO => binary(BinOp::CheckedISub),
+                MULO => binary(BinOp::CheckedMul),
+                IMULO => binary(BinOp::CheckedIMul),
+                _ => return Err(anyhow!("Invalid checked instruction: 0x{insn:04x}")),
+            },
+            JUMP => Insn::Jump {
+                target: jump(sign_extend::<12>(insn & 0xfff))?,
+            },
+            LJUMP => {
+                let Some(&low) = code.get(i + 1) else {
+                    return Err(anyhow!("Invalid long jump: 0x{insn:04x}"));
+                };
+                operands[i + 1] = Some(i);
+                Insn::Jump {
+                    target: jump(long_offset(insn, low))?,
+                }
+            }
+            JUMPZ => Insn::JumpZ {
+                cond: a,
+                target: jump(sign_extend::<9>((insn & 0xff8) >> 3))?,
+            },
+            JUMPNZ => Insn::JumpNz {
+                cond: a,
+                target: jump(sign_extend::<9>((insn & 0xff8) >> 3))?,
+            },
+            BRANCH => {
+                let (Some(cond), Some(&offset)) = (Cond::of(insn), code.get(i + 1)) else {
+                    return Err(anyhow!("Invalid branch: 0x{insn:04x}"));
+                };
+                operands[i + 1] = Some(i);
+                Insn::Branch {
+                    cond,
+                    a,
+                    b,
+                    target: jump(offset as i16 as i64)?,
+                }
+            }
+            SWITCH => {
+                let len = ((insn & 0xff8) >> 3) as usize;
+                // The default behind the table has to exist.
+                if i + len + 1 >= code.len() {
+                    return Err(anyhow!("Invalid switch: 0x{insn:04x}"));
+                }
+                operands[i + 1..=i + len].fill(Some(i));
+                Insn::Switch { reg: a, len }
+            }
+            CALL => {
+                let func = (insn & 0xfff) as usize;
+                if func >= funcs {
+                    return Err(anyhow!("Invalid function: 0x{insn:04x}"));
+                }
+                if i + 1 >= code.len() {
+                    return Err(anyhow!("Invalid call: 0x{insn:04x}"));
+                }
+                Insn::Call { func }
+            }
+            _ => return Err(anyhow!("Invalid instruction: 0x{insn:04x}")),
+        });
+    }
+    if let Some(i) = insns.iter().position(|insn| {
+        insn.target()
+            .is_some_and(|target| operands[target].is_some())
+    }) {
+        return Err(anyhow!("Jump into an operand: 0x{:04x}", code[i]));
+    }
+    Ok(insns)
+}
+
+/// Marks every instruction that starts a basic block.
+pub fn block_starts(insns: &[Insn]) -> Vec<bool> {
+    let mut starts = vec![false; insns.len() + 1];
+    starts[0] = true;
+    for (i, insn) in insns.iter().enumerate() {
+        if let Some(target) = insn.target() {
+            starts[target] = true;
+            starts[i + 1] = true;
+        }
+        if insn.is_terminator() {
+            starts[i + 1] = true;
+        }
+        // Native code jumps to the default behind the table, which may have no cases.
+        if let Insn::Switch { len, .. } = insn {
+            starts[i + len + 1] = true;
+        }
+    }
+    starts.truncate(insns.len());
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Function, Insn};
+    use crate::opcodes::{__add, __halt, __jump, __jumpz, __ljump, __load, __return, __switch};
+
+    #[test]
+    fn test_blocks_and_liveness() {
+        let code = [
+            __load(0, 1),
+            __jumpz(1, 3),
+            __add(0, 0),
+            __jump(2),
+            __load(2, 0),
+            __return(),
+        ];
+        let func = Function::new(&code, 1).unwrap();
+        let ranges = func
+            .blocks
+            .iter()
+            .map(|block| (block.start, block.end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [(0, 2), (2, 4), (4, 5), (5, 6)]);
+        assert_eq!(func.blocks[0].succs, [2, 1]);
+        assert_eq!(func.blocks[3].preds, [1, 2]);
+        assert_eq!(func.blocks[0].uses, 0b010);
+        assert_eq!(func.blocks[0].defs, 0b001);
+        assert_eq!(func.blocks[1].live_in, 0xff);
+        assert_eq!(func.blocks[2].live_in, 0xfb);
+    }
+
+    #[test]
+    fn test_long_jump() {
+        let [high, low] = __ljump(3);
+        let func = Function::new(&[high, low, __load(0, 1), __return()], 1).unwrap();
+        assert_eq!(func.insns[..2], [Insn::Jump { target: 3 }, Insn::Nop]);
+        assert!(Function::new(&[__jump(2), high, low, __return()], 1).is_err());
+        assert!(Function::new(&[__load(0, 1), high], 1).is_err());
+    }
+
+    #[test]
+    fn test_switch() {
+        let code = [__switch(2, 2), 4, 3, __halt(), __return()];
+        let func = Function::new(&code, 1).unwrap();
+        assert_eq!(
+            func.insns[..3],
+            [
+                Insn::Switch { reg: 2, len: 2 },
+                Insn::Case { target: 4 },
+                Insn::Case { target: 3 },
+            ]
+        );
+        // Both cases and the default follow the `SWITCH`.
+        let starts = func
+            .blocks
+            .iter()
+            .map(|block| block.start)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0, 2, 3, 4]);
+        assert_eq!(func.blocks[0].succs, [3, 1]);
+        assert_eq!(func.blocks[1].succs, [2]);
+        assert_eq!(func.blocks[0].uses, 1 << 2);
+        // Jumping into the table, a case out of range and a missing default are rejected.
+        assert!(Function::new(&[__jump(2), __switch(0, 1), 2, __return()], 1).is_err());
+        assert!(Function::new(&[__switch(0, 1), 5, __return()], 1).is_err());
+        assert!(Function::new(&[__load(0, 1), __switch(0, 1), 0], 1).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("CPU must be 64-bit");
+
+pub mod asm;
+pub mod builder;
+pub mod cache;
+#[cfg(test)]
+mod conformance;
+pub mod debugger;
+pub mod debuginfo;
+pub mod decode;
+pub mod feedback;
+#[cfg(test)]
+mod fuzz;
+pub mod gdbstub;
+#[cfg(test)]
+mod harness;
+pub mod ir;
+pub mod opcodes;
+pub mod opt;
+pub mod perf;
+pub mod profiler;
+pub mod runtime;
+pub mod trace;
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        builder::Builder,
+        feedback::Feedback,
+        opcodes::{
+            __add, __bge, __blt, __call, __div, __halt, __jump, __jumpnz, __jumpz, __ljump, __load,
+            __move, __mul, __noop, __return, __sub,
+        },
+        profiler::{Profiler, ReportFormat},
+        runtime::{Context, Func, Runner},
+    };
+
+    fn ctx(code: &[u16]) -> (Context, Runner) {
+        let mut ctx = Context::default();
+        let runner = Runner::default();
+        let main = Func::new([__call(1), __return()].to_vec());
+        let func = Func::new(code.to_vec());
+        ctx.funcs.push(main);
+        ctx.funcs.push(func);
+        ctx.pc = ctx.funcs[0].addr.address as *const u16;
+        (ctx, runner)
+    }
+
+    fn ctx_with_helper(code: &[u16], helper: &[u16]) -> (Context, Runner) {
+        let (mut ctx, runner) = ctx(code);
+        ctx.funcs.push(Func::new(helper.to_vec()));
+        (ctx, runner)
+    }
+
+    /// Runs `code` calling `helper` as function 2 in both tiers and compares every register.
+    ///
+    /// The helper is also compiled after `code`, so native calls into it have to be patched.
+    fn assert_same_with_helper(code: &[u16], helper: &[u16]) {
+        let (mut ctx, mut runner) = ctx_with_helper(code, helper);
+        runner.run(&mut ctx);
+        for compiled in [&[1][..], &[1, 2]] {
+            let (mut jitted, mut runner) = ctx_with_helper(code, helper);
+            for &index in compiled {
+                jitted.compile(index).unwrap();
+            }
+            runner.run(&mut jitted);
+            assert_same_regs(&ctx, &jitted);
+        }
+    }
+
+    /// Runs until the program returns, resuming behind every `HALT` once `on_halt` ran.
+    fn run_resuming(ctx: &mut Context, runner: &mut Runner, mut on_halt: impl FnMut(&mut Context)) {
+        runner.run(ctx);
+        while unsafe { *ctx.pc } == __halt() {
+            on_halt(ctx);
+            ctx.pc = unsafe { ctx.pc.add(1) };
+            runner.run(ctx);
+        }
+    }
+
+    fn assert_same_regs(ctx: &Context, jitted: &Context) {
+        for (reg, (a, b)) in ctx.regs.iter().zip(jitted.regs.iter()).enumerate() {
+            assert_eq!(unsafe { a.int }, unsafe { b.int }, "r{reg}");
+        }
+    }
+
+    #[test]
+    fn test_relaxed_jumps() {
+        let mut builder = Builder::default();
+        let (skip, end) = (builder.label(), builder.label());
+        builder.push(__load(0, 1));
+        builder.jump(__jumpz(1, 0), skip);
+        builder.push(__add(0, 0));
+        builder.extend([__noop(); 300]);
+        builder.bind(skip);
+        builder.jump(__jump(0), end);
+        builder.push(__add(0, 0));
+        builder.extend([__noop(); 3000]);
+        builder.bind(end);
+        builder.push(__return());
+        let code = builder.finish().unwrap();
+        assert_eq!(code[1], __jumpnz(1, 3));
+        assert_eq!(code[2..4], __ljump(303));
+        assert_eq!(code[305..307], __ljump(3003));
+        assert_same_with_helper(&code, &[__return()]);
+    }
+
+    #[test]
+    fn test_relaxed_branches() {
+        let mut builder = Builder::default();
+        let (skip, end) = (builder.label(), builder.label());
+        builder.extend([__load(0, 1), __load(1, 2)]);
+        // Taken, then not taken.
+        builder.jump(__blt(0, 1, 0)[0], skip);
+        builder.push(__add(0, 0));
+        builder.extend([__noop(); 40000]);
+        builder.bind(skip);
+        builder.jump(__bge(0, 1, 0)[0], end);
+        builder.push(__add(0, 0));
+        builder.extend([__noop(); 40000]);
+        builder.bind(end);
+        builder.push(__return());
+        let code = builder.finish().unwrap();
+        assert_eq!(code[2..6], [__bge(0, 1, 4), __ljump(40003)].concat());
+        assert_eq!(
+            code[40007..40011],
+            [__blt(0, 1, 4), __ljump(40003)].concat()
+        );
+        assert_same_with_helper(&code, &[__return()]);
+    }
+
+    #[test]
+    fn test_patched_native_calls() {
+        let code = [__load(0, 1), __call(2), __call(2), __return()];
+        let mut helper = vec![__add(0, 0); 20];
+        helper.push(__return());
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        ctx.compile(1).unwrap();
+        let sites = ctx.funcs[2].callers.len();
+        assert!(sites > 0);
+        ctx.compile(2).unwrap();
+        ctx.compile(2).unwrap();
+        runner.run(&mut ctx);
+        assert_eq!(unsafe { ctx.regs[0].int }, 1 << 40);
+        ctx.compile(1).unwrap();
+        assert_eq!(ctx.funcs[2].callers.len(), sites);
+    }
+
+    #[test]
+    fn test_retired_code_kept_while_reachable() {
+        let code = [__load(0, 1), __add(0, 0), __return()];
+        let (mut ctx, mut runner) = ctx(&code);
+        ctx.compile(1).unwrap();
+        let frame = ctx.funcs[1].func as *const ();
+        ctx.callstack.push(frame);
+        ctx.compile(1).unwrap();
+        ctx.compile(1).unwrap();
+        assert_eq!(ctx.code.retired(), 1);
+        ctx.callstack.pop();
+        ctx.compile(1).unwrap();
+        assert_eq!(ctx.code.retired(), 0);
+        runner.run(&mut ctx);
+        assert_eq!(unsafe { ctx.regs[0].int }, 2);
+    }
+
+    #[test]
+    fn test_deoptimize_running_frames() {
+        let code = [
+            __load(0, 1),
+            __call(2),
+            __add(0, 0),
+            __call(2),
+            __add(0, 0),
+            __return(),
+        ];
+        let mut helper = vec![__add(1, 0); 17];
+        helper.extend([__halt(), __return()]);
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        run_resuming(&mut ctx, &mut runner, |_| {});
+        let (mut jitted, mut runner) = ctx_with_helper(&code, &helper);
+        jitted.compile(1).unwrap();
+        run_resuming(&mut jitted, &mut runner, |ctx| {
+            ctx.deoptimize(1).unwrap();
+            assert!(!ctx.funcs[1].addr.native);
+            assert_eq!(ctx.code.retired(), 0);
+        });
+        assert_same_regs(&ctx, &jitted);
+    }
+
+    #[test]
+    fn test_deoptimize_inlined_and_recursive_frames() {
+        let code = [
+            __load(0, 1),
+            __load(1, 3),
+            __load(2, 1),
+            __call(3),
+            __return(),
+        ];
+        let mut helper = vec![__add(4, 0); 17];
+        helper.extend([__halt(), __return()]);
+        let recursive = [
+            __jumpz(1, 5),
+            __sub(1, 2),
+            __call(3),
+            __add(0, 0),
+            __return(),
+            __call(2),
+            __return(),
+        ];
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        ctx.funcs.push(Func::new(recursive.to_vec()));
+        run_resuming(&mut ctx, &mut runner, |_| {});
+        for deoptimized in [&[3][..], &[1], &[1, 3]] {
+            let (mut jitted, mut runner) = ctx_with_helper(&code, &helper);
+            jitted.funcs.push(Func::new(recursive.to_vec()));
+            jitted.compile(3).unwrap();
+            jitted.compile(1).unwrap();
+            run_resuming(&mut jitted, &mut runner, |ctx| {
+                for &index in deoptimized {
+                    ctx.deoptimize(index).unwrap();
+                }
+            });
+            assert_same_regs(&ctx, &jitted);
+        }
+    }
+
+    #[test]
+    fn test_speculation_guards() {
+        let code = [__call(2), __add(0, 0), __return()];
+        let helper = [
+            __move(3, 1),
+            __load(0, 200),
+            __div(0, 3),
+            __jumpz(2, 2),
+            __add(0, 0),
+            __return(),
+        ];
+        let run_with = |ctx: &mut Context, runner: &mut Runner, r1: i64, r2: i64| {
+            ctx.regs[1].int = r1;
+            ctx.regs[2].int = r2;
+            ctx.pc = ctx.funcs[0].addr.address as *const u16;
+            runner.run(ctx);
+            unsafe { ctx.regs[0].int }
+        };
+        let (mut jitted, mut runner) = ctx_with_helper(&code, &helper);
+        jitted.feedback = Some(Feedback::default());
+        for _ in 0..Feedback::MIN_COUNT {
+            run_with(&mut jitted, &mut runner, 4, 1);
+        }
+        for index in [1, 2] {
+            jitted.compile(index).unwrap();
+            for (r1, r2) in [(4, 1), (5, 1), (4, 0), (1, 0), (4, 1)] {
+                let (mut ctx, mut ctx_runner) = ctx_with_helper(&code, &helper);
+                assert_eq!(
+                    run_with(&mut jitted, &mut runner, r1, r2),
+                    run_with(&mut ctx, &mut ctx_runner, r1, r2),
+                    "r1 = {r1}, r2 = {r2}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_profiler_counts() {
+        let code = [__load(0, 1), __call(2), __call(2), __return()];
+        let mut helper = vec![__add(0, 0); 17];
+        helper.push(__return());
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        ctx.profiler = Some(Profiler::new(ReportFormat::Json));
+        ctx.compile(2).unwrap();
+        runner.run(&mut ctx);
+        let profiler = ctx.profiler.as_ref().unwrap();
+        assert_eq!(profiler.funcs[0].insns, 2);
+        assert_eq!(profiler.funcs[0].interpreted_calls, 1);
+        assert_eq!(profiler.funcs[1].insns, 4);
+        assert_eq!(profiler.funcs[1].interpreted_calls, 1);
+        assert_eq!(profiler.funcs[2].insns, 0);
+        assert_eq!(profiler.funcs[2].native_calls.get(), 2);
+        assert!(profiler.funcs[2].code_size > 0);
+        assert_eq!(profiler.opcodes["CALL"], 3);
+        assert!(profiler.report().starts_with("{\"funcs\":[{\"func\":0,"));
+        // The compiled code keeps its counter alive once the profiler is gone.
+        let native_calls = profiler.funcs[2].native_calls.clone();
+        ctx.profiler = None;
+        ctx.pc = ctx.funcs[0].addr.address as *const u16;
+        runner.run(&mut ctx);
+        assert_eq!(native_calls.get(), 4);
+    }
+
+    #[test]
+    fn test_inlined_helper() {
+        let code = [
+            __load(0, 1),
+            __load(1, 7),
+            __call(2),
+            __call(2),
+            __move(3, 0),
+            __return(),
+        ];
+        let helper = [__add(0, 1), __mul(0, 1), __return()];
+        assert_same_with_helper(&code, &helper);
+    }
+
+    #[test]
+    fn test_inlined_recursive_helper() {
+        let code = [__load(1, 5), __load(2, 1), __call(2), __return()];
+        let helper = [
+            __jumpz(1, 5),
+            __add(0, 1),
+            __sub(1, 2),
+            __call(2),
+            __return(),
+            __return(),
+        ];
+        assert_same_with_helper(&code, &helper);
+    }
+
+    #[test]
+    fn test_large_helper_not_inlined() {
+        let code = [__load(0, 2), __call(2), __call(2), __return()];
+        let mut helper = vec![__add(0, 0); 20];
+        helper.push(__return());
+        assert_same_with_helper(&code, &helper);
+    }
+
+    #[test]
+    fn test_trap_backtrace() {
+        let code = [__load(0, 1), __call(2), __return()];
+        let helper = [__load(1, 2), 0xf000, __return()];
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        runner.run(&mut ctx);
+        assert_eq!(
+            ctx.format_backtrace(),
+            "#0 func2+1\n#1 func1+1\n#2 func0+0\n"
+        );
+        let (mut ctx, mut runner) = ctx_with_helper(&code, &helper);
+        ctx.compile(1).unwrap();
+        runner.run(&mut ctx);
+        assert_eq!(
+            ctx.format_backtrace(),
+            "#0 func2+1\n#1 func1+1 [native]\n#2 func0+0\n"
+        );
+    }
+}
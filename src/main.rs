@@ -1,12 +1,12 @@
-#[cfg(not(target_pointer_width = "64"))]
-compile_error!("CPU must be 64-bit");
-
-pub mod asm;
-pub mod opcodes;
-pub mod runtime;
-
-use opcodes::{__call, __iload, __imul, __print, __return};
-use runtime::{Context, Func, Runner};
+use jit_testing::{
+    debugger::Debugger,
+    gdbstub::GdbStub,
+    opcodes::{__call, __iload, __imul, __print, __return},
+    perf,
+    profiler::{Profiler, ReportFormat},
+    runtime::{Context, Func, Runner},
+    trace::Tracer,
+};
 
 fn main() {
     let main = [__call(1), __return()];
@@ -19,88 +19,30 @@ fn main() {
     ];
     let mut ctx = Context::default();
     let mut runner = Runner::default();
-    let main = Func::new(main.to_vec());
-    let jitted = Func::new(code.to_vec());
+    match std::env::var("JIT_PERF").as_deref() {
+        Ok("jitdump") => perf::enable(true).unwrap(),
+        Ok(_) => perf::enable(false).unwrap(),
+        Err(_) => {}
+    }
+    let main = Func::named("main", main.to_vec());
+    let jitted = Func::named("jitted", code.to_vec());
     ctx.funcs.push(main);
     ctx.funcs.push(jitted);
-    Func::compile(&mut ctx.funcs, 1).unwrap();
-    ctx.pc = ctx.funcs[0].addr.address as _;
-    runner.run(&mut ctx);
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::{
-        opcodes::{__add, __call, __div, __idiv, __iload, __load, __mul, __return, __sub},
-        runtime::{Context, Func, Runner},
+    ctx.profiler = match std::env::var("JIT_PROFILE").as_deref() {
+        Ok("json") => Some(Profiler::new(ReportFormat::Json)),
+        Ok(_) => Some(Profiler::new(ReportFormat::Table)),
+        Err(_) => None,
     };
-
-    fn ctx(code: &[u16]) -> (Context, Runner) {
-        let mut ctx = Context::default();
-        let runner = Runner::default();
-        let main = Func::new([__call(1), __return()].to_vec());
-        let func = Func::new(code.to_vec());
-        ctx.funcs.push(main);
-        ctx.funcs.push(func);
-        ctx.pc = ctx.funcs[0].addr.address as *const u16;
-        (ctx, runner)
-    }
-
-    fn run(code: &[u16]) -> Context {
-        let (mut ctx, mut runner) = ctx(code);
-        runner.run(&mut ctx);
-        ctx
+    if let Ok(path) = std::env::var("JIT_TRACE_INSNS").or(std::env::var("JIT_TRACE")) {
+        let insns = std::env::var_os("JIT_TRACE_INSNS").is_some();
+        ctx.tracer = Some(Tracer::create(path, insns).unwrap());
     }
-
-    fn run_jitted(code: &[u16]) -> Context {
-        let (mut ctx, mut runner) = ctx(code);
-        Func::compile(&mut ctx.funcs, 1).unwrap();
-        runner.run(&mut ctx);
-        ctx
-    }
-
-    #[test]
-    fn test_addition() {
-        let code = [__load(0, 3), __load(1, 5), __add(0, 1), __return()];
-        let ctx = run(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 8);
-        let ctx = run_jitted(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 8);
-    }
-
-    #[test]
-    fn test_subtraction() {
-        let code = [__load(0, 3), __load(1, 5), __sub(0, 1), __return()];
-        let ctx = run(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, -2);
-        let ctx = run_jitted(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, -2);
-    }
-
-    #[test]
-    fn test_multiplication() {
-        let code = [__load(0, 3), __load(1, 5), __mul(0, 1), __return()];
-        let ctx = run(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 15);
-        let ctx = run_jitted(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 15);
-    }
-
-    #[test]
-    fn test_division() {
-        let code = [__load(0, 15), __load(1, 5), __div(0, 1), __return()];
-        let ctx = run(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 3);
-        let ctx = run_jitted(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, 3);
-    }
-
-    #[test]
-    fn test_signed_division() {
-        let code = [__iload(0, 15), __iload(1, -5), __idiv(0, 1), __return()];
-        let ctx = run(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, -3);
-        let ctx = run_jitted(&code);
-        assert_eq!(unsafe { ctx.regs[0].int }, -3);
+    if let Ok(addr) = std::env::var("JIT_GDB") {
+        ctx.debugger = Some(Box::new(GdbStub::listen(addr).unwrap()));
+    } else if std::env::var_os("JIT_DEBUG").is_some() {
+        ctx.debugger = Some(Box::new(Debugger::default()));
     }
+    ctx.compile(1).unwrap();
+    ctx.pc = ctx.funcs[0].addr.address as _;
+    runner.run(&mut ctx);
 }
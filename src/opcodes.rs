@@ -17,6 +17,46 @@ pub const HALT: u16 = 0x0e00;
 
 pub const LOAD: u16 = 0x1000;
 pub const ILOAD: u16 = 0x2000;
+pub const FLOATOP: u16 = 0x3000;
+pub const FADD: u16 = 0x3000;
+pub const FSUB: u16 = 0x3100;
+pub const FMUL: u16 = 0x3200;
+pub const FDIV: u16 = 0x3300;
+pub const FCMP: u16 = 0x3400;
+pub const ITOF: u16 = 0x3500;
+pub const FTOI: u16 = 0x3600;
+pub const PRINTF: u16 = 0x3700;
+pub const CHECKEDOP: u16 = 0x4000;
+pub const ADDO: u16 = 0x4000;
+pub const IADDO: u16 = 0x4100;
+pub const SUBO: u16 = 0x4200;
+pub const ISUBO: u16 = 0x4300;
+pub const MULO: u16 = 0x4400;
+pub const IMULO: u16 = 0x4500;
+/// Three-register instructions, with the operation in bits 9 to 11 and the third register in
+/// bits 6 to 8.
+pub const WIDEOP: u16 = 0x5000;
+pub const MULH: u16 = 0x5000;
+pub const MULHU: u16 = 0x5200;
+pub const DIVREM: u16 = 0x5400;
+pub const IDIVREM: u16 = 0x5600;
+/// Branches comparing two registers, with the first register in bits 0 to 2, the second in
+/// bits 3 to 5 and the condition in bits 6 to 8. The following word holds the offset. Bit 6
+/// negates the condition.
+pub const BRANCH: u16 = 0x6000;
+pub const BEQ: u16 = 0x6000;
+pub const BNE: u16 = 0x6040;
+pub const BLT: u16 = 0x6080;
+pub const BGE: u16 = 0x60c0;
+pub const BLTU: u16 = 0x6100;
+pub const BGEU: u16 = 0x6140;
+/// Jumps by a 28-bit offset, with the upper 12 bits in the instruction and the lower 16 bits in
+/// the following word.
+pub const LJUMP: u16 = 0x9000;
+/// Jumps through the table of `len` offsets following the instruction, with the register in
+/// bits 0 to 2 and `len` in bits 3 to 11. Each offset is a word relative to the `SWITCH`; a
+/// register not below `len` continues behind the table.
+pub const SWITCH: u16 = 0xa000;
 pub const JUMP: u16 = 0xb000;
 pub const JUMPZ: u16 = 0xc000;
 pub const JUMPNZ: u16 = 0xd000;
@@ -82,6 +122,78 @@ pub fn __halt() -> u16 {
     HALT
 }
 
+pub fn __fadd(dst: u16, src: u16) -> u16 {
+    FADD | dst & 7 | (src & 7) << 3
+}
+
+pub fn __fsub(dst: u16, src: u16) -> u16 {
+    FSUB | dst & 7 | (src & 7) << 3
+}
+
+pub fn __fmul(dst: u16, src: u16) -> u16 {
+    FMUL | dst & 7 | (src & 7) << 3
+}
+
+pub fn __fdiv(dst: u16, src: u16) -> u16 {
+    FDIV | dst & 7 | (src & 7) << 3
+}
+
+pub fn __fcmp(dst: u16, src: u16) -> u16 {
+    FCMP | dst & 7 | (src & 7) << 3
+}
+
+pub fn __itof(dst: u16, src: u16) -> u16 {
+    ITOF | dst & 7 | (src & 7) << 3
+}
+
+pub fn __ftoi(dst: u16, src: u16) -> u16 {
+    FTOI | dst & 7 | (src & 7) << 3
+}
+
+pub fn __printf(src: u16) -> u16 {
+    PRINTF | src & 7
+}
+
+pub fn __addo(dst: u16, src: u16) -> u16 {
+    ADDO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __iaddo(dst: u16, src: u16) -> u16 {
+    IADDO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __subo(dst: u16, src: u16) -> u16 {
+    SUBO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __isubo(dst: u16, src: u16) -> u16 {
+    ISUBO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __mulo(dst: u16, src: u16) -> u16 {
+    MULO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __imulo(dst: u16, src: u16) -> u16 {
+    IMULO | dst & 7 | (src & 7) << 3
+}
+
+pub fn __mulh(dst: u16, src: u16) -> u16 {
+    MULH | dst & 7 | (src & 7) << 3
+}
+
+pub fn __mulhu(dst: u16, src: u16) -> u16 {
+    MULHU | dst & 7 | (src & 7) << 3
+}
+
+pub fn __divrem(dst: u16, rem: u16, src: u16) -> u16 {
+    DIVREM | dst & 7 | (src & 7) << 3 | (rem & 7) << 6
+}
+
+pub fn __idivrem(dst: u16, rem: u16, src: u16) -> u16 {
+    IDIVREM | dst & 7 | (src & 7) << 3 | (rem & 7) << 6
+}
+
 pub fn __load(dst: u16, value: u16) -> u16 {
     LOAD | dst & 7 | (value & 0x1ff) << 3
 }
@@ -94,6 +206,14 @@ pub fn __jump(offset: i16) -> u16 {
     JUMP | offset as u16 & 0xfff
 }
 
+pub fn __ljump(offset: i32) -> [u16; 2] {
+    [LJUMP | (offset >> 16) as u16 & 0xfff, offset as u16]
+}
+
+pub fn __switch(reg: u16, len: u16) -> u16 {
+    SWITCH | reg & 7 | (len & 0x1ff) << 3
+}
+
 pub fn __jumpz(dst: u16, offset: i16) -> u16 {
     JUMPZ | dst & 7 | (offset as u16 & 0x1ff) << 3
 }
@@ -102,6 +222,99 @@ pub fn __jumpnz(dst: u16, offset: i16) -> u16 {
     JUMPNZ | dst & 7 | (offset as u16 & 0x1ff) << 3
 }
 
+pub fn __beq(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BEQ | a & 7 | (b & 7) << 3, offset as u16]
+}
+
+pub fn __bne(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BNE | a & 7 | (b & 7) << 3, offset as u16]
+}
+
+pub fn __blt(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BLT | a & 7 | (b & 7) << 3, offset as u16]
+}
+
+pub fn __bge(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BGE | a & 7 | (b & 7) << 3, offset as u16]
+}
+
+pub fn __bltu(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BLTU | a & 7 | (b & 7) << 3, offset as u16]
+}
+
+pub fn __bgeu(a: u16, b: u16, offset: i16) -> [u16; 2] {
+    [BGEU | a & 7 | (b & 7) << 3, offset as u16]
+}
+
 pub fn __call(index: u16) -> u16 {
     CALL | index & 0xfff
 }
+
+/// Returns the mnemonic of an encoded instruction.
+pub fn mnemonic(insn: u16) -> &'static str {
+    match insn & 0xf000 {
+        SMALLOP => match insn & 0xf00 {
+            NOOP => "NOOP",
+            MOVE => "MOVE",
+            MEMLOAD => "MEMLOAD",
+            MEMSTORE => "MEMSTORE",
+            RETURN => "RETURN",
+            ADD => "ADD",
+            SUB => "SUB",
+            MUL => "MUL",
+            IMUL => "IMUL",
+            DIV => "DIV",
+            IDIV => "IDIV",
+            REM => "REM",
+            IREM => "IREM",
+            PRINT => "PRINT",
+            HALT => "HALT",
+            _ => "INVALID",
+        },
+        LOAD => "LOAD",
+        ILOAD => "ILOAD",
+        FLOATOP => match insn & 0xff00 {
+            FADD => "FADD",
+            FSUB => "FSUB",
+            FMUL => "FMUL",
+            FDIV => "FDIV",
+            FCMP => "FCMP",
+            ITOF => "ITOF",
+            FTOI => "FTOI",
+            PRINTF => "PRINTF",
+            _ => "INVALID",
+        },
+        CHECKEDOP => match insn & 0xff00 {
+            ADDO => "ADDO",
+            IADDO => "IADDO",
+            SUBO => "SUBO",
+            ISUBO => "ISUBO",
+            MULO => "MULO",
+            IMULO => "IMULO",
+            _ => "INVALID",
+        },
+        WIDEOP => match insn & 0xfe00 {
+            MULH => "MULH",
+            MULHU => "MULHU",
+            DIVREM => "DIVREM",
+            IDIVREM => "IDIVREM",
+            _ => "INVALID",
+        },
+        BRANCH => match insn & 0xffc0 {
+            BEQ => "BEQ",
+            BNE => "BNE",
+            BLT => "BLT",
+            BGE => "BGE",
+            BLTU => "BLTU",
+            BGEU => "BGEU",
+            _ => "INVALID",
+        },
+        LJUMP => "LJUMP",
+        SWITCH => "SWITCH",
+        JUMP => "JUMP",
+        JUMPZ => "JUMPZ",
+        JUMPNZ => "JUMPNZ",
+        CALL => "CALL",
+        _ => "INVALID",
+    }
+}
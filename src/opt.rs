@@ -0,0 +1,527 @@
+use crate::{
+    feedback::{Feedback, Observed},
+    ir::{decode, BinOp, Check, Function, Inlined, Insn, Operand, Speculated},
+    runtime::Func,
+};
+
+/// Maximum number of instructions of a callee that is inlined.
+pub const INLINE_SIZE: usize = 16;
+/// Maximum number of instructions inlining may add to a function.
+pub const INLINE_BUDGET: usize = 64;
+
+/// Runs every optimization pass over a decoded function.
+///
+/// Removed instructions become [`Insn::Nop`] so bytecode offsets stay valid. The function is
+/// analyzed again afterwards.
+pub fn optimize(func: &mut Function) {
+    fold_constants(func);
+    reduce_strength(func);
+    remove_redundant_moves(func);
+    thread_jumps(func);
+    func.analyze();
+    eliminate_dead_stores(func);
+    func.analyze();
+}
+
+/// Copies small callees into `func` at their `CALL` sites.
+///
+/// `index` is the index of `func` in `funcs`. Callees that are already being inlined, including
+/// `func` itself, are left as calls, as are callees that may fall off their end.
+pub fn inline(func: &mut Function, index: usize, funcs: &[Func]) {
+    let mut budget = INLINE_BUDGET;
+    let len = func.insns.len();
+    inline_calls(func, 0..len, &mut vec![index], funcs, &mut budget);
+    func.analyze();
+}
+
+fn inline_calls(
+    func: &mut Function,
+    sites: std::ops::Range<usize>,
+    stack: &mut Vec<usize>,
+    funcs: &[Func],
+    budget: &mut usize,
+) {
+    for site in sites {
+        let Insn::Call { func: callee } = func.insns[site] else {
+            continue;
+        };
+        let code = &funcs[callee].code;
+        if stack.contains(&callee) || code.len() > INLINE_SIZE || code.len() > *budget {
+            continue;
+        }
+        let Ok(insns) = decode(code, funcs.len()) else {
+            continue;
+        };
+        if !insns.last().is_some_and(Insn::is_terminator) {
+            continue;
+        }
+        *budget -= insns.len();
+        let start = func.insns.len();
+        for mut insn in insns {
+            if let Some(target) = insn.target() {
+                insn.set_target(start + target);
+            }
+            if let Insn::Return = insn {
+                insn = Insn::Jump { target: site + 1 };
+            }
+            func.insns.push(insn);
+        }
+        func.insns[site] = Insn::Jump { target: start };
+        func.inlined.push(Inlined {
+            func: callee,
+            site,
+            start,
+            len: code.len(),
+        });
+        stack.push(callee);
+        inline_calls(func, start..func.insns.len(), stack, funcs, budget);
+        stack.pop();
+    }
+}
+
+/// Replaces instructions with guarded versions specialized for the interpreter's feedback.
+///
+/// Unsigned divisions and remainders by a register that always held the same power of two
+/// become guarded shifts and masks. `index` is the index of `func` in `funcs`. Run before
+/// [`optimize`], which propagates the values guards establish.
+pub fn speculate(func: &mut Function, index: usize, funcs: &[Func], feedback: &Feedback) {
+    for insn in 0..func.insns.len() {
+        let frame = func.frames(index, insn)[0];
+        let Some(site) = feedback
+            .get(&funcs[frame.func].code[frame.pc])
+            .filter(|site| site.count >= Feedback::MIN_COUNT)
+        else {
+            continue;
+        };
+        func.insns[insn] = match func.insns[insn] {
+            Insn::Binary {
+                op: op @ (BinOp::Div | BinOp::Rem),
+                dst,
+                src: Operand::Reg(src),
+            } => {
+                let Observed::Const(value) = site.value else {
+                    continue;
+                };
+                if !(value as u64).is_power_of_two() {
+                    continue;
+                }
+                let then = if op == BinOp::Div {
+                    Speculated {
+                        op: BinOp::Shr,
+                        dst,
+                        value: value.trailing_zeros() as i64,
+                    }
+                } else {
+                    Speculated {
+                        op: BinOp::And,
+                        dst,
+                        value: value - 1,
+                    }
+                };
+                Insn::Guard {
+                    reg: src,
+                    check: Check::Eq(value),
+                    then: Some(then),
+                }
+            }
+            _ => continue,
+        };
+    }
+    func.analyze();
+}
+
+/// Propagates `LOAD`ed constants within basic blocks and evaluates operations on them.
+pub fn fold_constants(func: &mut Function) {
+    for block in &func.blocks {
+        let mut known = [None; 8];
+        for insn in &mut func.insns[block.start..block.end] {
+            fold_constant(insn, &mut known);
+        }
+    }
+}
+
+fn fold_constant(insn: &mut Insn, known: &mut [Option<i64>; 8]) {
+    if let Insn::Guard { reg, check, then } = *insn {
+        if known[reg as usize].is_some_and(|value| check.passes(value)) {
+            *insn = then.map_or(Insn::Nop, |then| Insn::Binary {
+                op: then.op,
+                dst: then.dst,
+                src: Operand::Imm(then.value),
+            });
+        }
+    }
+    match *insn {
+        Insn::Move { dst, src } => {
+            if let Some(value) = known[src as usize] {
+                *insn = Insn::Const { dst, value };
+            }
+        }
+        Insn::Unary { op, dst, src } => {
+            if let Some(value) = known[src as usize] {
+                *insn = Insn::Const {
+                    dst,
+                    value: op.eval(value),
+                };
+            }
+        }
+        Insn::Binary {
+            op,
+            dst,
+            src: Operand::Reg(src),
+        } => {
+            if let Some(value) = known[src as usize] {
+                *insn = Insn::Binary {
+                    op,
+                    dst,
+                    src: Operand::Imm(value),
+                };
+            }
+        }
+        _ => {}
+    }
+    if let Insn::Binary {
+        op,
+        dst,
+        src: Operand::Imm(b),
+    } = *insn
+    {
+        if let Some(value) = known[dst as usize].and_then(|a| op.eval(a, b)) {
+            *insn = Insn::Const { dst, value };
+        }
+    }
+    match *insn {
+        Insn::Const { dst, value } => known[dst as usize] = Some(value),
+        Insn::Call { .. } => *known = [None; 8],
+        Insn::Guard { reg, check, then } => {
+            if let Some(then) = then {
+                known[then.dst as usize] = None;
+            }
+            // Execution only continues if the check passed.
+            if insn.writes() & (1 << reg) == 0 {
+                known[reg as usize] = known[reg as usize].or(check.value());
+            }
+        }
+        _ => {
+            for (reg, value) in known.iter_mut().enumerate() {
+                if insn.writes() & (1 << reg) != 0 {
+                    *value = None;
+                }
+            }
+        }
+    }
+}
+
+/// Replaces multiplications, divisions and remainders by powers of two with shifts and masks
+/// and drops identity operations.
+pub fn reduce_strength(func: &mut Function) {
+    for insn in func.insns.iter_mut() {
+        let Insn::Binary {
+            op,
+            dst,
+            src: Operand::Imm(value),
+        } = *insn
+        else {
+            continue;
+        };
+        let pow2 = (value as u64).is_power_of_two();
+        let shift = Operand::Imm(value.trailing_zeros() as i64);
+        *insn = match (op, value) {
+            (BinOp::Add | BinOp::Sub | BinOp::Shl | BinOp::Shr, 0) => Insn::Nop,
+            (BinOp::Mul | BinOp::Div | BinOp::IDiv, 1) => Insn::Nop,
+            (BinOp::Mul, 0) => Insn::Const { dst, value: 0 },
+            (BinOp::Mul, _) if pow2 => Insn::Binary {
+                op: BinOp::Shl,
+                dst,
+                src: shift,
+            },
+            (BinOp::Div, _) if pow2 => Insn::Binary {
+                op: BinOp::Shr,
+                dst,
+                src: shift,
+            },
+            (BinOp::Rem, _) if pow2 => Insn::Binary {
+                op: BinOp::And,
+                dst,
+                src: Operand::Imm(value.wrapping_sub(1)),
+            },
+            _ => continue,
+        };
+    }
+}
+
+/// Removes `MOVE`s whose destination already holds the source value.
+pub fn remove_redundant_moves(func: &mut Function) {
+    for block in &func.blocks {
+        // copies[dst] = Some(src) while `dst` holds the same value as `src`.
+        let mut copies = [None; 8];
+        for insn in &mut func.insns[block.start..block.end] {
+            remove_redundant_move(insn, &mut copies);
+        }
+    }
+}
+
+fn remove_redundant_move(insn: &mut Insn, copies: &mut [Option<u8>; 8]) {
+    if let Insn::Move { dst, src } = *insn {
+        if dst == src || copies[dst as usize] == Some(src) || copies[src as usize] == Some(dst) {
+            *insn = Insn::Nop;
+            return;
+        }
+    }
+    if let Insn::Call { .. } = insn {
+        *copies = [None; 8];
+    }
+    let writes = insn.writes();
+    for copy in copies.iter_mut() {
+        if matches!(copy, Some(src) if writes & (1 << *src) != 0) {
+            *copy = None;
+        }
+    }
+    for (reg, copy) in copies.iter_mut().enumerate() {
+        if writes & (1 << reg) != 0 {
+            *copy = None;
+        }
+    }
+    if let Insn::Move { dst, src } = *insn {
+        copies[dst as usize] = Some(src);
+    }
+}
+
+/// Retargets jumps that land on unconditional jumps and removes jumps to the next instruction.
+///
+/// [`Insn::Case`]s are only retargeted, since removing one would shift the rest of its table.
+pub fn thread_jumps(func: &mut Function) {
+    let insns = &mut func.insns;
+    let resolve = |insns: &[Insn], mut target: usize| {
+        // Bounded so that jump cycles terminate.
+        for _ in 0..insns.len() {
+            match insns[target] {
+                Insn::Jump { target: next } => target = next,
+                _ => break,
+            }
+        }
+        target
+    };
+    for i in 0..insns.len() {
+        let mut insn = insns[i];
+        let Some(target) = insn.target() else {
+            continue;
+        };
+        insn.set_target(resolve(insns, target));
+        insns[i] = if insn.target() == Some(i + 1) && !matches!(insn, Insn::Case { .. }) {
+            Insn::Nop
+        } else {
+            insn
+        };
+    }
+}
+
+/// Removes side-effect free instructions whose result is overwritten before it is read.
+///
+/// Uses the liveness computed by [`Function::analyze`]; every register is live at calls,
+/// halts and returns, where other code can observe `Context::regs`.
+pub fn eliminate_dead_stores(func: &mut Function) {
+    for block in &func.blocks {
+        let mut live = block.live_out;
+        for insn in func.insns[block.start..block.end].iter_mut().rev() {
+            if insn.is_pure() && insn.writes() & live == 0 {
+                *insn = Insn::Nop;
+                continue;
+            }
+            live &= !insn.writes();
+            live |= insn.reads();
+            if insn.observes_regs() {
+                live = 0xff;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inline, optimize, speculate};
+    use crate::{
+        feedback::Feedback,
+        ir::{BinOp, Check, Function, Inlined, Insn, Operand, Speculated},
+        opcodes::{
+            __add, __call, __div, __jump, __jumpz, __load, __move, __mul, __print, __rem, __return,
+            __switch,
+        },
+        runtime::Func,
+    };
+
+    fn optimized(code: &[u16]) -> Vec<Insn> {
+        let mut func = Function::new(code, 1).unwrap();
+        optimize(&mut func);
+        func.insns
+    }
+
+    #[test]
+    fn test_fold_load_add_chain() {
+        let code = [
+            __load(0, 3),
+            __load(1, 5),
+            __add(0, 1),
+            __add(0, 1),
+            __return(),
+        ];
+        let insns = optimized(&code);
+        assert_eq!(
+            insns,
+            [
+                Insn::Nop,
+                Insn::Const { dst: 1, value: 5 },
+                Insn::Nop,
+                Insn::Const { dst: 0, value: 13 },
+                Insn::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strength_reduction() {
+        let code = [
+            __load(1, 8),
+            __mul(0, 1),
+            __rem(2, 1),
+            __print(0),
+            __return(),
+        ];
+        let insns = optimized(&code);
+        assert_eq!(
+            insns[1],
+            Insn::Binary {
+                op: BinOp::Shl,
+                dst: 0,
+                src: Operand::Imm(3)
+            }
+        );
+        assert_eq!(
+            insns[2],
+            Insn::Binary {
+                op: BinOp::And,
+                dst: 2,
+                src: Operand::Imm(7)
+            }
+        );
+    }
+
+    #[test]
+    fn test_redundant_moves() {
+        let code = [__move(1, 0), __move(0, 1), __move(2, 2), __return()];
+        let insns = optimized(&code);
+        assert_eq!(
+            insns,
+            [
+                Insn::Move { dst: 1, src: 0 },
+                Insn::Nop,
+                Insn::Nop,
+                Insn::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_threading() {
+        let code = [__jump(2), __return(), __jump(2), __jump(1), __return()];
+        let insns = optimized(&code);
+        assert_eq!(insns[0], Insn::Jump { target: 4 });
+        assert_eq!(insns[3], Insn::Nop);
+    }
+
+    #[test]
+    fn test_jump_threading_keeps_cases() {
+        // The second case jumps to the next instruction, but removing it would shorten the table.
+        let code = [__switch(0, 2), 3, 3, __return()];
+        let insns = optimized(&code);
+        assert_eq!(
+            insns[..3],
+            [
+                Insn::Switch { reg: 0, len: 2 },
+                Insn::Case { target: 3 },
+                Insn::Case { target: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dead_stores_across_blocks() {
+        let code = [
+            __load(0, 1),
+            __jumpz(1, 3),
+            __load(0, 2),
+            __jump(2),
+            __load(0, 3),
+            __return(),
+        ];
+        let insns = optimized(&code);
+        assert_eq!(insns[0], Insn::Nop);
+        assert_eq!(insns[2], Insn::Const { dst: 0, value: 2 });
+    }
+
+    #[test]
+    fn test_inline_with_recursion_guard() {
+        let funcs = [
+            Func::new([__call(1), __return()].to_vec()),
+            Func::new([__add(0, 0), __call(1), __return()].to_vec()),
+        ];
+        let mut func = Function::new(&funcs[0].code, funcs.len()).unwrap();
+        inline(&mut func, 0, &funcs);
+        assert_eq!(
+            func.insns,
+            [
+                Insn::Jump { target: 2 },
+                Insn::Return,
+                Insn::Binary {
+                    op: BinOp::Add,
+                    dst: 0,
+                    src: Operand::Reg(0)
+                },
+                Insn::Call { func: 1 },
+                Insn::Jump { target: 1 },
+            ]
+        );
+        assert_eq!(
+            func.inlined,
+            [Inlined {
+                func: 1,
+                site: 0,
+                start: 2,
+                len: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn test_speculate_from_feedback() {
+        let funcs = [Func::new(
+            [__div(0, 1), __add(0, 1), __print(0), __return()].to_vec(),
+        )];
+        let code = &funcs[0].code;
+        let mut feedback = Feedback::default();
+        for _ in 0..Feedback::MIN_COUNT {
+            feedback.record_value(&code[0], 8);
+        }
+        let mut func = Function::new(code, funcs.len()).unwrap();
+        speculate(&mut func, 0, &funcs, &feedback);
+        optimize(&mut func);
+        assert_eq!(
+            func.insns[..2],
+            [
+                Insn::Guard {
+                    reg: 1,
+                    check: Check::Eq(8),
+                    then: Some(Speculated {
+                        op: BinOp::Shr,
+                        dst: 0,
+                        value: 3
+                    })
+                },
+                Insn::Binary {
+                    op: BinOp::Add,
+                    dst: 0,
+                    src: Operand::Imm(8)
+                },
+            ]
+        );
+    }
+}
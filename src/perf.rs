@@ -0,0 +1,210 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::debuginfo::ELF_MACHINE;
+
+/// Files describing JIT code to Linux `perf`, written while enabled by [`enable`].
+static PERF: Mutex<Option<Perf>> = Mutex::new(None);
+
+/// Starts describing every stub and compiled function to `perf`.
+///
+/// Symbols are appended to `/tmp/perf-<pid>.map`. With `jitdump`, code is also recorded in
+/// `jit-<pid>.dump` in the current directory for `perf inject --jit`. Only affects code
+/// generated afterwards.
+pub fn enable(jitdump: bool) -> io::Result<()> {
+    let pid = std::process::id();
+    let map = PerfMap::create(format!("/tmp/perf-{pid}.map"))?;
+    let dump = if jitdump {
+        Some(JitDump::create(format!("jit-{pid}.dump"))?)
+    } else {
+        None
+    };
+    *PERF.lock().unwrap() = Some(Perf { map, dump });
+    Ok(())
+}
+
+/// Describes `code` as the symbol `name` if `perf` support is enabled.
+pub fn record(name: &str, code: &[u8]) {
+    let mut perf = PERF.lock().unwrap();
+    let Some(perf) = perf.as_mut() else {
+        return;
+    };
+    let mut result = perf.map.record(name, code);
+    if let Some(dump) = &mut perf.dump {
+        result = result.and(dump.record(name, code));
+    }
+    if let Err(err) = result {
+        eprintln!("Could not record {name} for perf: {err}");
+    }
+}
+
+struct Perf {
+    map: PerfMap,
+    dump: Option<JitDump>,
+}
+
+/// A `perf-<pid>.map` file: one `START SIZE symbol` line per code region.
+pub struct PerfMap {
+    file: File,
+}
+
+impl PerfMap {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, name: &str, code: &[u8]) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "{:x} {:x} {name}",
+            code.as_ptr() as usize,
+            code.len()
+        )
+    }
+}
+
+/// A jitdump file as specified in `tools/perf/Documentation/jitdump-specification.txt`.
+pub struct JitDump {
+    file: File,
+    /// Number of code regions recorded so far.
+    index: u64,
+    /// `perf record` only notices the dump if it is mapped executable.
+    #[cfg(target_os = "linux")]
+    marker: *mut libc::c_void,
+}
+
+// The marker mapping is never accessed.
+unsafe impl Send for JitDump {}
+
+impl JitDump {
+    const MAGIC: u32 = 0x4a695444;
+    const VERSION: u32 = 1;
+    const HEADER_SIZE: u32 = 40;
+    const CODE_LOAD: u32 = 0;
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE as usize);
+        header.extend(Self::MAGIC.to_ne_bytes());
+        header.extend(Self::VERSION.to_ne_bytes());
+        header.extend(Self::HEADER_SIZE.to_ne_bytes());
+        header.extend(u32::from(ELF_MACHINE).to_ne_bytes());
+        header.extend(0u32.to_ne_bytes());
+        header.extend(std::process::id().to_ne_bytes());
+        header.extend(timestamp().to_ne_bytes());
+        header.extend(0u64.to_ne_bytes());
+        file.write_all(&header)?;
+        #[cfg(target_os = "linux")]
+        let marker = unsafe {
+            use std::os::fd::AsRawFd;
+
+            let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let marker = libc::mmap(
+                std::ptr::null_mut(),
+                page,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            );
+            if marker == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            marker
+        };
+        Ok(Self {
+            file,
+            index: 0,
+            #[cfg(target_os = "linux")]
+            marker,
+        })
+    }
+
+    pub fn record(&mut self, name: &str, code: &[u8]) -> io::Result<()> {
+        let size = 16 + 40 + name.len() + 1 + code.len();
+        let mut record = Vec::with_capacity(size);
+        record.extend(Self::CODE_LOAD.to_ne_bytes());
+        record.extend((size as u32).to_ne_bytes());
+        record.extend(timestamp().to_ne_bytes());
+        record.extend(std::process::id().to_ne_bytes());
+        record.extend(thread_id().to_ne_bytes());
+        record.extend((code.as_ptr() as u64).to_ne_bytes());
+        record.extend((code.as_ptr() as u64).to_ne_bytes());
+        record.extend((code.len() as u64).to_ne_bytes());
+        record.extend(self.index.to_ne_bytes());
+        record.extend(name.as_bytes());
+        record.push(0);
+        record.extend(code);
+        self.index += 1;
+        self.file.write_all(&record)
+    }
+}
+
+impl Drop for JitDump {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::munmap(self.marker, libc::sysconf(libc::_SC_PAGESIZE) as usize);
+        }
+    }
+}
+
+/// Nanoseconds on the clock `perf record -k mono` uses.
+#[cfg(target_os = "linux")]
+fn timestamp() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn timestamp() -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u32 {
+    unsafe { libc::gettid() as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JitDump, PerfMap};
+
+    #[test]
+    fn test_perf_map_and_jitdump() {
+        let dir = std::env::temp_dir().join(format!("jit-testing-perf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let code = [0xc3u8; 5];
+        let mut map = PerfMap::create(dir.join("perf.map")).unwrap();
+        map.record("func1", &code).unwrap();
+        let mut dump = JitDump::create(dir.join("jit.dump")).unwrap();
+        dump.record("func1", &code).unwrap();
+        drop(dump);
+        let map = std::fs::read_to_string(dir.join("perf.map")).unwrap();
+        assert_eq!(map, format!("{:x} 5 func1\n", code.as_ptr() as usize));
+        let dump = std::fs::read(dir.join("jit.dump")).unwrap();
+        assert_eq!(dump[..4], JitDump::MAGIC.to_ne_bytes());
+        assert_eq!(dump.len(), 40 + 16 + 40 + 6 + 5);
+        assert_eq!(dump[dump.len() - 11..dump.len() - 5], *b"func1\0");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
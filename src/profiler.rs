@@ -0,0 +1,210 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    opcodes::{mnemonic, CALL},
+    runtime::Func,
+};
+
+/// Format of the report the profiler emits when the runner exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+/// Counters collected for one function.
+#[derive(Debug, Default)]
+pub struct FuncStats {
+    /// Instructions the interpreter executed in the function.
+    pub insns: u64,
+    /// Calls that entered the interpreter at the start of the function.
+    pub interpreted_calls: u64,
+    /// Calls into compiled code, counted by the code itself. The code shares the counter, so
+    /// it stays valid after the profiler is dropped.
+    pub native_calls: Rc<Cell<u64>>,
+    /// Time spent interpreting the function.
+    pub interpreted_time: Duration,
+    /// Time spent in compiled code entered from the interpreter through this function,
+    /// including the compiled functions it calls.
+    pub native_time: Duration,
+    pub compile_time: Duration,
+    /// Size of the compiled code in bytes.
+    pub code_size: usize,
+}
+
+/// Opt-in execution profiler, enabled by setting `Context::profiler`.
+///
+/// The interpreter reports every instruction it executes and every transition into compiled
+/// code. Time is attributed to whatever ran since the previous report, so it includes the
+/// profiler's own overhead.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub format: ReportFormat,
+    /// Instructions the interpreter executed, by mnemonic.
+    pub opcodes: BTreeMap<&'static str, u64>,
+    /// Counters of each function, indexed like `Context::funcs`.
+    pub funcs: Vec<FuncStats>,
+    /// Start and end of the bytecode of each function and its index, sorted by start.
+    ranges: Vec<(*const u16, *const u16, usize)>,
+    /// The function and tier time is currently attributed to.
+    current: Option<(usize, bool)>,
+    last: Option<Instant>,
+    /// The next instruction at the start of a function is a call.
+    entering: bool,
+}
+
+impl Profiler {
+    pub fn new(format: ReportFormat) -> Self {
+        Self {
+            format,
+            entering: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the counters of `func`, growing the table as functions are added.
+    pub fn stats(&mut self, func: usize) -> &mut FuncStats {
+        if self.funcs.len() <= func {
+            self.funcs.resize_with(func + 1, FuncStats::default);
+        }
+        &mut self.funcs[func]
+    }
+
+    /// Records that the interpreter is about to execute `insn` at `pc`.
+    pub fn step(&mut self, pc: *const u16, insn: u16, funcs: &[Func]) {
+        self.attribute();
+        let Some(func) = self.func_of(pc, funcs) else {
+            self.current = None;
+            return;
+        };
+        let entering = self.entering && pc == funcs[func].code.as_ptr();
+        let stats = self.stats(func);
+        stats.insns += 1;
+        stats.interpreted_calls += entering as u64;
+        *self.opcodes.entry(mnemonic(insn)).or_default() += 1;
+        self.entering = insn & 0xf000 == CALL;
+        self.current = Some((func, false));
+    }
+
+    /// Records that the interpreter is about to enter the compiled code of `func`.
+    pub fn enter_native(&mut self, func: usize) {
+        self.attribute();
+        self.current = Some((func, true));
+        self.entering = true;
+    }
+
+    /// Attributes the time since the last report to the current function.
+    pub fn attribute(&mut self) {
+        let now = Instant::now();
+        let elapsed = self.last.map_or(Duration::ZERO, |last| now - last);
+        self.last = Some(now);
+        if let Some((func, native)) = self.current {
+            let stats = self.stats(func);
+            if native {
+                stats.native_time += elapsed;
+            } else {
+                stats.interpreted_time += elapsed;
+            }
+        }
+    }
+
+    /// Stops attributing time until the interpreter runs again.
+    pub fn pause(&mut self) {
+        self.attribute();
+        self.current = None;
+        self.last = None;
+    }
+
+    fn func_of(&mut self, pc: *const u16, funcs: &[Func]) -> Option<usize> {
+        if self.ranges.len() != funcs.len() {
+            self.ranges = funcs
+                .iter()
+                .enumerate()
+                .map(|(index, func)| (func.code.as_ptr(), func.code.as_ptr_range().end, index))
+                .collect();
+            self.ranges.sort();
+        }
+        let next = self.ranges.partition_point(|(start, _, _)| *start <= pc);
+        let (_, end, index) = *self.ranges.get(next.checked_sub(1)?)?;
+        (pc < end).then_some(index)
+    }
+
+    /// Formats the collected counters in `format`.
+    pub fn report(&self) -> String {
+        match self.format {
+            ReportFormat::Table => self.table(),
+            ReportFormat::Json => self.json(),
+        }
+    }
+
+    fn table(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:>5} {:>12} {:>10} {:>10} {:>12} {:>12} {:>12} {:>8}",
+            "func", "insns", "calls", "native", "time", "native time", "compile", "code"
+        )
+        .unwrap();
+        for (index, stats) in self.funcs.iter().enumerate() {
+            writeln!(
+                out,
+                "{:>5} {:>12} {:>10} {:>10} {:>12} {:>12} {:>12} {:>8}",
+                index,
+                stats.insns,
+                stats.interpreted_calls,
+                stats.native_calls.get(),
+                format!("{:.1?}", stats.interpreted_time),
+                format!("{:.1?}", stats.native_time),
+                format!("{:.1?}", stats.compile_time),
+                stats.code_size
+            )
+            .unwrap();
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "{:>10} {:>12}", "opcode", "count").unwrap();
+        for (opcode, count) in &self.opcodes {
+            writeln!(out, "{opcode:>10} {count:>12}").unwrap();
+        }
+        out
+    }
+
+    fn json(&self) -> String {
+        let funcs = self
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(index, stats)| {
+                format!(
+                    "{{\"func\":{},\"insns\":{},\"interpreted_calls\":{},\"native_calls\":{},\
+                     \"interpreted_time_ns\":{},\"native_time_ns\":{},\"compile_time_ns\":{},\
+                     \"code_size\":{}}}",
+                    index,
+                    stats.insns,
+                    stats.interpreted_calls,
+                    stats.native_calls.get(),
+                    stats.interpreted_time.as_nanos(),
+                    stats.native_time.as_nanos(),
+                    stats.compile_time.as_nanos(),
+                    stats.code_size
+                )
+            })
+            .collect::<Vec<_>>();
+        let opcodes = self
+            .opcodes
+            .iter()
+            .map(|(opcode, count)| format!("\"{opcode}\":{count}"))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"funcs\":[{}],\"opcodes\":{{{}}}}}\n",
+            funcs.join(","),
+            opcodes.join(",")
+        )
+    }
+}
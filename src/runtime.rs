@@ -1,21 +1,40 @@
 use std::{
     alloc::{alloc, dealloc, Layout},
-    collections::HashMap,
+    cell::Cell,
     mem,
     ptr::{null, null_mut},
+    rc::Rc,
+    slice,
+    time::Instant,
 };
 
 use anyhow::anyhow;
-use dynasmrt::{dynasm, Assembler, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use dynasmrt::{
+    dynasm,
+    relocations::{Relocation, RelocationSize},
+    Assembler, AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer,
+};
 
 use crate::{
     asm::{
-        call_virtual_native, halt, print, return_native_virtual, return_virtual_native, snapshot,
+        call_virtual_native, enter_virtual, halt, print, printf, return_native_virtual,
+        return_virtual_native, snapshot, trace,
     },
+    cache::{Code, CodeCache},
+    debugger::DebugHook,
+    debuginfo::Registration,
+    decode::{decode, Op},
+    feedback::Feedback,
+    ir::{self, BinOp, Check, Cond, Frame, Insn, Operand, UnOp},
     opcodes::{
-        ADD, CALL, DIV, HALT, IDIV, ILOAD, IMUL, IREM, JUMP, JUMPNZ, JUMPZ, LOAD, MEMLOAD,
-        MEMSTORE, MOVE, MUL, NOOP, PRINT, REM, RETURN, SMALLOP, SUB,
+        ADD, ADDO, BRANCH, CALL, CHECKEDOP, DIV, DIVREM, FADD, FCMP, FDIV, FLOATOP, FMUL, FSUB,
+        FTOI, HALT, IADDO, IDIV, IDIVREM, ILOAD, IMUL, IMULO, IREM, ISUBO, ITOF, JUMP, JUMPNZ,
+        JUMPZ, LJUMP, LOAD, MEMLOAD, MEMSTORE, MOVE, MUL, MULH, MULHU, MULO, NOOP, PRINT, PRINTF,
+        REM, RETURN, SMALLOP, SUB, SUBO, SWITCH, WIDEOP,
     },
+    opt, perf,
+    profiler::Profiler,
+    trace::Tracer,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -55,6 +74,8 @@ pub union Value {
     pub uint: u64,
     pub int: i64,
     pub size: usize,
+    /// The register as an IEEE 754 double, as used by the float instructions.
+    pub float: f64,
 }
 
 #[repr(C)]
@@ -79,14 +100,22 @@ pub struct Runner {
 impl Runner {
     pub fn run(&mut self, ctx: &mut Context) {
         self.ctx = ctx;
+        self.running = true;
         self._run();
+        if let Some(tracer) = &mut ctx.tracer {
+            tracer.flush(&ctx.regs);
+        }
+        if let Some(profiler) = &mut ctx.profiler {
+            profiler.pause();
+            eprint!("{}", profiler.report());
+        }
     }
 
     #[inline(never)]
     fn _run(&mut self) {
         unsafe { snapshot(self) };
         while self.running {
-            unsafe { &mut *self.ctx }.step(self);
+            unsafe { &mut *self.ctx }.interpret(self);
         }
     }
 }
@@ -109,11 +138,22 @@ pub struct Stack<T> {
 }
 
 impl<T> Stack<T> {
+    /// Entries allocated beyond `size`. Compiled code that runs into the limit pushes the
+    /// frames it deoptimizes into below it, and the interpreter pushes two entries when calling
+    /// native code after checking for one.
+    const SLACK: usize = opt::INLINE_BUDGET + 4;
+
     pub fn new(size: usize) -> Self {
-        let bp = unsafe { (alloc(Layout::array::<T>(size).unwrap()) as *mut T).add(size) };
+        let layout = Layout::array::<T>(size + Self::SLACK).unwrap();
+        let bp = unsafe { (alloc(layout) as *mut T).add(size + Self::SLACK) };
         Self { size, bp, sp: bp }
     }
 
+    /// Returns the lowest address entries may be pushed to before the stack overflows.
+    pub fn limit(&self) -> *const T {
+        unsafe { self.bp.sub(self.size) }
+    }
+
     pub fn push(&mut self, value: T) {
         unsafe {
             self.sp = self.sp.sub(1);
@@ -136,6 +176,27 @@ impl<T> Stack<T> {
     pub fn is_overflown(&self) -> bool {
         self.sp < unsafe { self.bp.sub(self.size) }
     }
+
+    /// Returns the pushed values, the most recently pushed first.
+    pub fn entries(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.sp, self.bp.offset_from(self.sp) as usize) }
+    }
+}
+
+impl<T: Copy> Stack<T> {
+    /// Replaces the pushed values, given the most recently pushed first.
+    ///
+    /// Returns `false` and leaves the stack unchanged if they do not fit.
+    pub fn set_entries(&mut self, entries: &[T]) -> bool {
+        if entries.len() > self.size {
+            return false;
+        }
+        unsafe {
+            self.sp = self.bp.sub(entries.len());
+            slice::from_raw_parts_mut(self.sp, entries.len()).copy_from_slice(entries);
+        }
+        true
+    }
 }
 
 impl<T: Copy> Stack<T> {
@@ -152,8 +213,8 @@ impl<T> Drop for Stack<T> {
     fn drop(&mut self) {
         unsafe {
             dealloc(
-                self.bp.sub(self.size) as *mut u8,
-                Layout::array::<T>(self.size).unwrap(),
+                self.bp.sub(self.size + Self::SLACK) as *mut u8,
+                Layout::array::<T>(self.size + Self::SLACK).unwrap(),
             )
         }
     }
@@ -166,11 +227,455 @@ pub struct Context {
     pub callstack: Stack<*const ()>,
     pub mem: *mut u8,
     pub funcs: Vec<Func>,
+    pub code: CodeCache,
+    /// Feedback for speculative compilation, collected while set.
+    pub feedback: Option<Feedback>,
+    /// Execution profiler, reporting whenever the runner exits while set.
+    pub profiler: Option<Profiler>,
+    /// Debugger consulted before every interpreted instruction while set.
+    pub debugger: Option<Box<dyn DebugHook>>,
+    /// Execution trace, written while set.
+    pub tracer: Option<Tracer>,
+    /// Numbers the guest printed, collected as the text written to stdout instead while set.
+    pub printed: Option<Vec<String>>,
+    /// The error that stopped the runner, if guest code trapped.
+    pub trapped: Option<Trap>,
+}
+
+/// An error in guest code that stops the runner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    InvalidInstruction(u16),
+    /// A `CALL` of a function that does not exist.
+    InvalidFunction(u16),
+    /// A `CALL` with a full callstack.
+    CallstackOverflow(u16),
+    /// Checked arithmetic whose result does not fit, or `IDIVREM` of `i64::MIN` by -1.
+    Overflow(u16),
+    /// `DIVREM` or `IDIVREM` by zero.
+    DivisionByZero(u16),
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trap::InvalidInstruction(insn) => write!(f, "Invalid instruction: 0x{insn:04x}"),
+            Trap::InvalidFunction(insn) => write!(f, "Invalid function: 0x{insn:04x}"),
+            Trap::CallstackOverflow(insn) => write!(f, "Callstack overflow: 0x{insn:04x}"),
+            Trap::Overflow(insn) => write!(f, "Arithmetic overflow: 0x{insn:04x}"),
+            Trap::DivisionByZero(insn) => write!(f, "Division by zero: 0x{insn:04x}"),
+        }
+    }
 }
 
 impl Context {
+    /// Compiles `funcs[index]` and frees retired code that no frame on the callstack can return
+    /// into anymore.
+    pub fn compile(&mut self, index: usize) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let options = CompileOptions {
+            feedback: self.feedback.as_ref(),
+            counter: self
+                .profiler
+                .as_mut()
+                .map(|profiler| &profiler.stats(index).native_calls),
+            trace: self.tracer.is_some(),
+            callstack_limit: self.callstack.limit(),
+        };
+        Func::compile(&mut self.funcs, &mut self.code, options, index)?;
+        if let Some(profiler) = &mut self.profiler {
+            let stats = profiler.stats(index);
+            stats.compile_time += start.elapsed();
+            stats.code_size = self.code.get(index).map_or(0, |code| code.buf.len());
+        }
+        self.code.collect(self.callstack.entries());
+        Ok(())
+    }
+
+    /// Reverts `funcs[index]` to the interpreter, including frames that are currently running
+    /// its native code.
+    ///
+    /// Compiled code keeps `regs` up to date at every call, so each native return address into
+    /// the function is replaced with the bytecode frames it resumes and the interpreter takes
+    /// over once the callee returns.
+    pub fn deoptimize(&mut self, index: usize) -> anyhow::Result<()> {
+        Func::invalidate(&mut self.funcs, &mut self.code, index)?;
+        let rnv = return_native_virtual as *const ();
+        let mut entries = Vec::with_capacity(self.callstack.entries().len());
+        // Set after a rewritten frame, whose own return address comes next.
+        let mut returning = false;
+        for &entry in self.callstack.entries() {
+            if mem::take(&mut returning) {
+                // Interpreted code returns to native code through a null marker and is
+                // returned to directly by the interpreter.
+                if entry == rnv {
+                    continue;
+                }
+                entries.push(null());
+            }
+            let Some(frames) = self
+                .code
+                .lookup(entry)
+                .filter(|code| code.func == index)
+                .and_then(|code| code.frames(entry))
+            else {
+                entries.push(entry);
+                continue;
+            };
+            // Native callees return through `return_native_virtual`, interpreted callees
+            // through the null marker, which becomes unnecessary.
+            if entries.last() == Some(&null()) {
+                entries.pop();
+            } else {
+                entries.push(rnv);
+            }
+            for frame in frames {
+                entries.push(bytecode(&self.funcs, *frame) as *const ());
+            }
+            returning = true;
+        }
+        if !self.callstack.set_entries(&entries) {
+            return Err(anyhow!(
+                "Callstack overflow while deoptimizing function {index}"
+            ));
+        }
+        self.code.collect(self.callstack.entries());
+        Ok(())
+    }
+
+    /// Reverts every compiled function to the interpreter, see [`Context::deoptimize`].
+    pub fn deoptimize_all(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.funcs.len() {
+            if self.code.get(index).is_some() {
+                self.deoptimize(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the bytecode location the interpreter is at followed by the call sites of the
+    /// frames on the callstack, innermost first, each with whether it runs compiled code.
+    ///
+    /// Native return addresses are translated to the call sites of the functions inlined at
+    /// them. The markers of transitions between the tiers are skipped.
+    pub fn backtrace(&self) -> Vec<(Frame, bool)> {
+        let mut frames =
+            Vec::from_iter(Func::locate(&self.funcs, self.pc).map(|frame| (frame, false)));
+        for &entry in self.callstack.entries() {
+            let (resumed, native) = match self.code.lookup(entry) {
+                Some(code) => (
+                    code.frames(entry)
+                        .map(<[Frame]>::to_vec)
+                        .unwrap_or_default(),
+                    true,
+                ),
+                None => (
+                    Vec::from_iter(Func::locate(&self.funcs, entry as *const u16)),
+                    false,
+                ),
+            };
+            frames.extend(resumed.into_iter().map(|frame| {
+                let site = Frame {
+                    func: frame.func,
+                    pc: frame.pc - 1,
+                };
+                (site, native)
+            }));
+        }
+        frames
+    }
+
+    /// Formats [`Context::backtrace`] with one numbered line per frame.
+    pub fn format_backtrace(&self) -> String {
+        let mut out = String::new();
+        for (index, (frame, native)) in self.backtrace().into_iter().enumerate() {
+            let symbol = Func::symbol(&self.funcs, frame.func);
+            let tier = if native { " [native]" } else { "" };
+            out += &format!("#{index} {symbol}+{}{tier}\n", frame.pc);
+        }
+        out
+    }
+
+    /// Stops the runner after an error in guest code, reporting it with a backtrace.
+    fn trap(&mut self, runner: &mut Runner, trap: Trap) {
+        runner.running = false;
+        self.trapped = Some(trap);
+        eprintln!("{trap}");
+        eprint!("{}", self.format_backtrace());
+    }
+
+    /// Prints `num` for the guest.
+    pub fn print(&mut self, num: i64) {
+        self.print_text(num.to_string());
+    }
+
+    /// Prints `num` for the guest like `{:?}`, so it always shows a fraction or exponent.
+    pub fn print_float(&mut self, num: f64) {
+        self.print_text(format!("{num:?}"));
+    }
+
+    fn print_text(&mut self, text: String) {
+        match &mut self.printed {
+            Some(printed) => printed.push(text),
+            None => println!("{text}"),
+        }
+    }
+
+    /// Executes instructions until the runner stops or the fast path has to hand over.
+    ///
+    /// Instructions run from the pre-decoded `Func::ops` in a loop that keeps `pc` local and
+    /// follows calls and returns between interpreted functions. Everything else, like
+    /// transitions to native code, traps, or hooks and feedback that observe every
+    /// instruction, is left to [`Context::step`] one instruction at a time.
+    pub fn interpret(&mut self, runner: &mut Runner) {
+        let observed = self.debugger.is_some()
+            || self.tracer.is_some()
+            || self.profiler.is_some()
+            || self.feedback.is_some();
+        let frame = match Func::locate(&self.funcs, self.pc) {
+            Some(frame) if !observed => frame,
+            _ => return self.step(runner),
+        };
+        let mut func = frame.func;
+        let mut pc = frame.pc;
+        let mut code = self.funcs[func].code.as_ptr();
+        let mut ops = self.funcs[func].ops.as_ptr();
+        // Functions that called into the loop, to switch back to on return.
+        let mut callers = Vec::new();
+        loop {
+            match unsafe { *ops.add(pc) } {
+                Op::Noop => {}
+                Op::Move { dst, src } => self.regs[dst as usize] = self.regs[src as usize],
+                Op::MemLoad { dst, src } => {
+                    let addr = unsafe { self.regs[src as usize].size } & 0xffff;
+                    self.regs[dst as usize] =
+                        unsafe { (self.mem.add(addr) as *const Value).read_unaligned() };
+                }
+                Op::MemStore { dst, src } => {
+                    let addr = unsafe { self.regs[dst as usize].size } & 0xffff;
+                    unsafe {
+                        (self.mem.add(addr) as *mut Value).write_unaligned(self.regs[src as usize])
+                    };
+                }
+                Op::Add { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let dst = &mut self.regs[dst as usize].uint;
+                    *dst = dst.wrapping_add(src);
+                },
+                Op::Sub { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let dst = &mut self.regs[dst as usize].uint;
+                    *dst = dst.wrapping_sub(src);
+                },
+                Op::Mul { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let dst = &mut self.regs[dst as usize].uint;
+                    *dst = dst.wrapping_mul(src);
+                },
+                Op::Imul { dst, src } => unsafe {
+                    let src = self.regs[src as usize].int;
+                    let dst = &mut self.regs[dst as usize].int;
+                    *dst = dst.wrapping_mul(src);
+                },
+                Op::Div { dst, src } => unsafe {
+                    self.regs[dst as usize].uint /= self.regs[src as usize].uint
+                },
+                Op::Idiv { dst, src } => unsafe {
+                    self.regs[dst as usize].int /= self.regs[src as usize].int
+                },
+                Op::Rem { dst, src } => unsafe {
+                    self.regs[dst as usize].uint %= self.regs[src as usize].uint
+                },
+                Op::Irem { dst, src } => unsafe {
+                    self.regs[dst as usize].int %= self.regs[src as usize].int
+                },
+                Op::FAdd { dst, src } => unsafe {
+                    let src = self.regs[src as usize].float;
+                    let dst = &mut self.regs[dst as usize].float;
+                    *dst = canonical_nan(*dst + src);
+                },
+                Op::FSub { dst, src } => unsafe {
+                    let src = self.regs[src as usize].float;
+                    let dst = &mut self.regs[dst as usize].float;
+                    *dst = canonical_nan(*dst - src);
+                },
+                Op::FMul { dst, src } => unsafe {
+                    let src = self.regs[src as usize].float;
+                    let dst = &mut self.regs[dst as usize].float;
+                    *dst = canonical_nan(*dst * src);
+                },
+                Op::FDiv { dst, src } => unsafe {
+                    let src = self.regs[src as usize].float;
+                    let dst = &mut self.regs[dst as usize].float;
+                    *dst = canonical_nan(*dst / src);
+                },
+                Op::FCmp { dst, src } => unsafe {
+                    self.regs[dst as usize].int =
+                        compare_floats(self.regs[dst as usize].float, self.regs[src as usize].float)
+                },
+                Op::IntToFloat { dst, src } => {
+                    self.regs[dst as usize].float = unsafe { self.regs[src as usize].int } as f64
+                }
+                Op::FloatToInt { dst, src } => {
+                    self.regs[dst as usize].int = unsafe { self.regs[src as usize].float } as i64
+                }
+                Op::AddO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let Some(value) = self.regs[dst as usize].uint.checked_add(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].uint = value;
+                },
+                Op::IAddO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].int;
+                    let Some(value) = self.regs[dst as usize].int.checked_add(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].int = value;
+                },
+                Op::SubO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let Some(value) = self.regs[dst as usize].uint.checked_sub(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].uint = value;
+                },
+                Op::ISubO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].int;
+                    let Some(value) = self.regs[dst as usize].int.checked_sub(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].int = value;
+                },
+                Op::MulO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint;
+                    let Some(value) = self.regs[dst as usize].uint.checked_mul(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].uint = value;
+                },
+                Op::IMulO { dst, src } => unsafe {
+                    let src = self.regs[src as usize].int;
+                    let Some(value) = self.regs[dst as usize].int.checked_mul(src) else {
+                        break;
+                    };
+                    self.regs[dst as usize].int = value;
+                },
+                Op::MulH { dst, src } => unsafe {
+                    let src = self.regs[src as usize].int as i128;
+                    let dst = &mut self.regs[dst as usize].int;
+                    *dst = ((*dst as i128 * src) >> 64) as i64;
+                },
+                Op::MulHU { dst, src } => unsafe {
+                    let src = self.regs[src as usize].uint as u128;
+                    let dst = &mut self.regs[dst as usize].uint;
+                    *dst = ((*dst as u128 * src) >> 64) as u64;
+                },
+                Op::DivRem { dst, rem, src } => unsafe {
+                    let (a, b) = (self.regs[dst as usize].uint, self.regs[src as usize].uint);
+                    let (Some(quotient), Some(remainder)) = (a.checked_div(b), a.checked_rem(b))
+                    else {
+                        break;
+                    };
+                    self.regs[dst as usize].uint = quotient;
+                    self.regs[rem as usize].uint = remainder;
+                },
+                Op::IDivRem { dst, rem, src } => unsafe {
+                    let (a, b) = (self.regs[dst as usize].int, self.regs[src as usize].int);
+                    let (Some(quotient), Some(remainder)) = (a.checked_div(b), a.checked_rem(b))
+                    else {
+                        break;
+                    };
+                    self.regs[dst as usize].int = quotient;
+                    self.regs[rem as usize].int = remainder;
+                },
+                Op::Load { dst, value } => self.regs[dst as usize].int = value as i64,
+                Op::Jump { target } => {
+                    pc = target as usize;
+                    continue;
+                }
+                Op::JumpZ { cond, target } => {
+                    if unsafe { self.regs[cond as usize].uint } == 0 {
+                        pc = target as usize;
+                        continue;
+                    }
+                }
+                Op::JumpNz { cond, target } => {
+                    if unsafe { self.regs[cond as usize].uint } != 0 {
+                        pc = target as usize;
+                        continue;
+                    }
+                }
+                Op::Branch { cond, a, b, target } => {
+                    let (a, b) = unsafe { (self.regs[a as usize].int, self.regs[b as usize].int) };
+                    pc = if cond.holds(a, b) {
+                        target as usize
+                    } else {
+                        pc + 2
+                    };
+                    continue;
+                }
+                Op::Switch { reg, len } => {
+                    let index = unsafe { self.regs[reg as usize].uint };
+                    let offset = if index < len as u64 {
+                        unsafe { *code.add(pc + 1 + index as usize) as i16 as isize }
+                    } else {
+                        len as isize + 1
+                    };
+                    pc = pc.wrapping_add_signed(offset);
+                    continue;
+                }
+                Op::Call { func: callee } => {
+                    let Some(f) = self.funcs.get(callee as usize) else {
+                        break;
+                    };
+                    if f.addr.native || self.callstack.will_overflow() {
+                        break;
+                    }
+                    self.callstack
+                        .push(unsafe { code.add(pc + 1) as *const () });
+                    callers.push(func);
+                    func = callee as usize;
+                    pc = 0;
+                    code = f.code.as_ptr();
+                    ops = f.ops.as_ptr();
+                    continue;
+                }
+                Op::Return => {
+                    let Some(caller) = callers.pop() else {
+                        break;
+                    };
+                    let ret = self.callstack.pop() as *const u16;
+                    func = caller;
+                    code = self.funcs[func].code.as_ptr();
+                    ops = self.funcs[func].ops.as_ptr();
+                    pc = unsafe { ret.offset_from(code) } as usize;
+                    continue;
+                }
+                Op::Slow => break,
+            }
+            pc += 1;
+        }
+        self.pc = unsafe { code.add(pc) };
+        self.step(runner);
+    }
+
     pub fn step(&mut self, runner: &mut Runner) {
+        if let Some(mut debugger) = self.debugger.take() {
+            let proceed = debugger.before_step(self);
+            self.debugger = Some(debugger);
+            if !proceed {
+                runner.running = false;
+                return;
+            }
+        }
         let insn = unsafe { *self.pc };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.step(self.pc, insn, &self.regs, &self.funcs);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.step(self.pc, insn, &self.funcs);
+        }
         let opc = insn & 0xf000;
         match opc {
             SMALLOP => {
@@ -186,13 +691,17 @@ impl Context {
                         let dst = insn & 0x7;
                         let src = (insn & 0x38) >> 3;
                         let addr = unsafe { self.regs[src as usize].size } & 0xffff;
-                        self.regs[dst as usize] = unsafe { *(self.mem.add(addr) as *const Value) };
+                        self.regs[dst as usize] =
+                            unsafe { (self.mem.add(addr) as *const Value).read_unaligned() };
                     }
                     MEMSTORE => {
                         let dst = insn & 0x7;
                         let src = (insn & 0x38) >> 3;
                         let addr = unsafe { self.regs[dst as usize].size } & 0xffff;
-                        unsafe { *(self.mem.add(addr) as *mut Value) = self.regs[src as usize] };
+                        unsafe {
+                            (self.mem.add(addr) as *mut Value)
+                                .write_unaligned(self.regs[src as usize])
+                        };
                     }
                     RETURN => {
                         if self.callstack.will_underflow() {
@@ -246,6 +755,9 @@ impl Context {
                     DIV => {
                         let dst = insn & 0x7;
                         let src = (insn & 0x38) >> 3;
+                        if let Some(feedback) = &mut self.feedback {
+                            feedback.record_value(self.pc, unsafe { self.regs[src as usize].int });
+                        }
                         unsafe { self.regs[dst as usize].uint /= self.regs[src as usize].uint };
                     }
                     IDIV => {
@@ -256,6 +768,9 @@ impl Context {
                     REM => {
                         let dst = insn & 0x7;
                         let src = (insn & 0x38) >> 3;
+                        if let Some(feedback) = &mut self.feedback {
+                            feedback.record_value(self.pc, unsafe { self.regs[src as usize].int });
+                        }
                         unsafe { self.regs[dst as usize].uint %= self.regs[src as usize].uint };
                     }
                     IREM => {
@@ -265,19 +780,93 @@ impl Context {
                     }
                     PRINT => {
                         let src = insn & 0x7;
-                        println!("{}", unsafe { self.regs[src as usize].int });
+                        self.print(unsafe { self.regs[src as usize].int });
                     }
                     HALT => {
                         runner.running = false;
                         return;
                     }
                     _ => {
-                        runner.running = false;
-                        eprintln!("Invalid small instruction: 0x{insn:04x}");
+                        self.trap(runner, Trap::InvalidInstruction(insn));
+                        return;
+                    }
+                }
+            }
+            FLOATOP => {
+                let dst = (insn & 0x7) as usize;
+                let src = ((insn & 0x38) >> 3) as usize;
+                let (a, b) = unsafe { (self.regs[dst].float, self.regs[src].float) };
+                match insn & 0xff00 {
+                    FADD => self.regs[dst].float = canonical_nan(a + b),
+                    FSUB => self.regs[dst].float = canonical_nan(a - b),
+                    FMUL => self.regs[dst].float = canonical_nan(a * b),
+                    FDIV => self.regs[dst].float = canonical_nan(a / b),
+                    FCMP => self.regs[dst].int = compare_floats(a, b),
+                    ITOF => self.regs[dst].float = unsafe { self.regs[src].int } as f64,
+                    FTOI => self.regs[dst].int = b as i64,
+                    PRINTF => self.print_float(a),
+                    _ => {
+                        self.trap(runner, Trap::InvalidInstruction(insn));
+                        return;
+                    }
+                }
+            }
+            WIDEOP => {
+                let dst = (insn & 0x7) as usize;
+                let src = ((insn & 0x38) >> 3) as usize;
+                let rem = ((insn & 0x1c0) >> 6) as usize;
+                let (a, b) = unsafe { (self.regs[dst].uint, self.regs[src].uint) };
+                let (ia, ib) = (a as i64, b as i64);
+                match insn & 0xfe00 {
+                    MULH => self.regs[dst].int = ((ia as i128 * ib as i128) >> 64) as i64,
+                    MULHU => self.regs[dst].uint = ((a as u128 * b as u128) >> 64) as u64,
+                    DIVREM | IDIVREM if b == 0 => {
+                        self.trap(runner, Trap::DivisionByZero(insn));
+                        return;
+                    }
+                    DIVREM => {
+                        self.regs[dst].uint = a / b;
+                        self.regs[rem].uint = a % b;
+                    }
+                    IDIVREM => {
+                        let (Some(quotient), Some(remainder)) =
+                            (ia.checked_div(ib), ia.checked_rem(ib))
+                        else {
+                            self.trap(runner, Trap::Overflow(insn));
+                            return;
+                        };
+                        self.regs[dst].int = quotient;
+                        self.regs[rem].int = remainder;
+                    }
+                    _ => {
+                        self.trap(runner, Trap::InvalidInstruction(insn));
                         return;
                     }
                 }
             }
+            CHECKEDOP => {
+                let dst = (insn & 0x7) as usize;
+                let src = ((insn & 0x38) >> 3) as usize;
+                let (a, b) = unsafe { (self.regs[dst].uint, self.regs[src].uint) };
+                let (ia, ib) = (a as i64, b as i64);
+                let value = match insn & 0xff00 {
+                    ADDO => a.checked_add(b),
+                    IADDO => ia.checked_add(ib).map(|value| value as u64),
+                    SUBO => a.checked_sub(b),
+                    ISUBO => ia.checked_sub(ib).map(|value| value as u64),
+                    MULO => a.checked_mul(b),
+                    IMULO => ia.checked_mul(ib).map(|value| value as u64),
+                    _ => {
+                        self.trap(runner, Trap::InvalidInstruction(insn));
+                        return;
+                    }
+                };
+                let Some(value) = value else {
+                    self.trap(runner, Trap::Overflow(insn));
+                    return;
+                };
+                self.regs[dst].uint = value;
+            }
             LOAD => {
                 let dst = insn & 0x7;
                 let value = (insn & 0xff8) >> 3;
@@ -293,6 +882,23 @@ impl Context {
                 self.pc = unsafe { self.pc.offset(offset as isize) };
                 return;
             }
+            LJUMP => {
+                let offset = long_offset(insn, unsafe { *self.pc.add(1) });
+                self.pc = unsafe { self.pc.offset(offset as isize) };
+                return;
+            }
+            SWITCH => {
+                let reg = insn & 0x7;
+                let len = (insn & 0xff8) >> 3;
+                let index = unsafe { self.regs[reg as usize].uint };
+                let offset = if index < len as u64 {
+                    unsafe { *self.pc.add(1 + index as usize) as i16 as isize }
+                } else {
+                    len as isize + 1
+                };
+                self.pc = unsafe { self.pc.offset(offset) };
+                return;
+            }
             JUMPZ => {
                 let cond = insn & 0x7;
                 let offset = sign_extend::<9>((insn & 0xff8) >> 3);
@@ -309,19 +915,37 @@ impl Context {
                     return;
                 }
             }
+            BRANCH => {
+                let Some(cond) = Cond::of(insn) else {
+                    self.trap(runner, Trap::InvalidInstruction(insn));
+                    return;
+                };
+                let a = insn & 0x7;
+                let b = (insn & 0x38) >> 3;
+                let (a, b) = unsafe { (self.regs[a as usize].int, self.regs[b as usize].int) };
+                // Not taken skips the offset word.
+                let offset = if cond.holds(a, b) {
+                    unsafe { *self.pc.add(1) as i16 as isize }
+                } else {
+                    2
+                };
+                self.pc = unsafe { self.pc.offset(offset) };
+                return;
+            }
             CALL => {
                 let index = insn & 0xfff;
                 let Some(func) = self.funcs.get(index as usize) else {
-                    runner.running = false;
-                    eprintln!("Invalid function: 0x{insn:04x}");
+                    self.trap(runner, Trap::InvalidFunction(insn));
                     return;
                 };
                 if self.callstack.will_overflow() {
-                    runner.running = false;
-                    eprintln!("Callstack overflow: 0x{insn:04x}");
+                    self.trap(runner, Trap::CallstackOverflow(insn));
                     return;
                 }
                 if func.addr.native {
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.enter_native(index as usize);
+                    }
                     self.callstack.push(unsafe { self.pc.add(1) as *const () });
                     self.callstack.push(return_native_virtual as *const ());
                     let addr = func.addr.address;
@@ -333,8 +957,7 @@ impl Context {
                 return;
             }
             _ => {
-                runner.running = false;
-                eprintln!("Invalid instruction: 0x{insn:04x}");
+                self.trap(runner, Trap::InvalidInstruction(insn));
                 return;
             }
         }
@@ -350,6 +973,13 @@ impl Default for Context {
             callstack: Stack::new(1024 * 4),
             mem: unsafe { alloc(Layout::array::<u8>(u16::MAX as usize + 8).unwrap()) },
             funcs: Vec::with_capacity(0),
+            code: CodeCache::default(),
+            feedback: None,
+            profiler: None,
+            debugger: None,
+            tracer: None,
+            printed: None,
+            trapped: None,
         }
     }
 }
@@ -357,668 +987,929 @@ impl Default for Context {
 impl Drop for Context {
     fn drop(&mut self) {
         unsafe {
-            dealloc(self.mem, Layout::array::<u8>(u16::MAX as usize).unwrap());
+            dealloc(
+                self.mem,
+                Layout::array::<u8>(u16::MAX as usize + 8).unwrap(),
+            );
         }
     }
 }
 
 pub type NativeAccessFunc = fn(*mut Runner, *mut Context);
 
+/// Settings for [`Func::compile`].
+#[derive(Clone, Copy, Default)]
+pub struct CompileOptions<'a> {
+    /// Interpreter feedback to speculate on.
+    pub feedback: Option<&'a Feedback>,
+    /// Counter the compiled code increments whenever it is called.
+    pub counter: Option<&'a Rc<Cell<u64>>>,
+    /// Call `Context::tracer` at the start of every block. Disables optimizations, so the
+    /// blocks are those the interpreter traces.
+    pub trace: bool,
+    /// `Stack::limit` of the callstack. Calls that would overflow it deoptimize, so the
+    /// interpreter traps.
+    pub callstack_limit: *const *const (),
+}
+
+/// Location of a patchable call target inside a compiled function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallSite {
+    /// Index of the calling function.
+    pub func: usize,
+    /// Offset of the 64-bit target address in the caller's code.
+    pub offset: usize,
+}
+
 pub struct Func {
+    /// Symbolic name used in profiles of the native code, see [`Func::symbol`].
+    pub name: Option<String>,
     pub code: Vec<u16>,
+    /// `code` decoded for `Context::interpret`.
+    pub ops: Vec<Op>,
     pub addr: Address,
+    /// Entry point for native callers, either the compiled code or `stub`.
     pub func: NativeAccessFunc,
-    pub buf: ExecutableBuffer,
+    /// Enters the interpreter at the start of `code`.
+    pub stub: NativeAccessFunc,
+    pub stub_buf: ExecutableBuffer,
+    /// Call sites in compiled functions that call this function. They are patched to the new
+    /// entry point whenever it is compiled.
+    pub callers: Vec<CallSite>,
 }
 
 impl Func {
     pub fn new(code: Vec<u16>) -> Self {
+        Self::with_name(None, code)
+    }
+
+    pub fn named(name: impl Into<String>, code: Vec<u16>) -> Self {
+        Self::with_name(Some(name.into()), code)
+    }
+
+    fn with_name(name: Option<String>, code: Vec<u16>) -> Self {
         let mut res = Self {
+            name,
+            ops: decode(&code),
             code,
             addr: Address {
                 native: false,
                 address: null(),
             },
             func: |_, _| {},
-            buf: ExecutableBuffer::default(),
+            stub: |_, _| {},
+            stub_buf: ExecutableBuffer::default(),
+            callers: Vec::with_capacity(0),
         };
         res.addr.address = res.code.as_ptr() as *const ();
-        let (buf, func) = generate_stub(res.addr.address);
-        res.func = func;
-        res.buf = buf;
+        let (buf, stub) = generate_stub(res.addr.address);
+        match &res.name {
+            Some(name) => perf::record(&format!("stub:{name}"), &buf),
+            None => perf::record(&format!("stub:{:p}", res.addr.address), &buf),
+        }
+        res.func = stub;
+        res.stub = stub;
+        res.stub_buf = buf;
         res
     }
 
+    /// Returns the name of `funcs[index]`, or one derived from its index if it has none.
+    pub fn symbol(funcs: &[Func], index: usize) -> String {
+        funcs[index]
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("func{index}"))
+    }
+
+    /// Returns the function and offset of the bytecode instruction at `pc`.
+    pub fn locate(funcs: &[Func], pc: *const u16) -> Option<Frame> {
+        funcs.iter().enumerate().find_map(|(func, f)| {
+            let pc = (pc as usize).checked_sub(f.code.as_ptr() as usize)? / 2;
+            (pc < f.code.len()).then_some(Frame { func, pc })
+        })
+    }
+
+    /// Installs freshly compiled code for `funcs[index]` and links it with other functions.
+    ///
+    /// `calls` lists the callee and target offset of every call site in `compiled`. Call sites that
+    /// call `funcs[index]` are patched to jump to `entry` directly, while the call sites of the
+    /// replaced code are redirected to the stubs of their callees before it is retired.
+    fn install(
+        funcs: &mut [Func],
+        code: &mut CodeCache,
+        mut compiled: Code,
+        entry: AssemblyOffset,
+        calls: Vec<(usize, usize)>,
+    ) -> anyhow::Result<()> {
+        let index = compiled.func;
+        let symbol = Func::symbol(funcs, index);
+        perf::record(&symbol, &compiled.buf);
+        compiled.debug = Some(Registration::new(&compiled, &symbol, |func| {
+            format!("{}.bc", Func::symbol(funcs, func))
+        }));
+        let redirects = Func::unlink(funcs, index);
+        for (callee, offset) in calls {
+            funcs[callee].callers.push(CallSite {
+                func: index,
+                offset,
+            });
+        }
+        let func = &mut funcs[index];
+        let exec =
+            unsafe { mem::transmute::<*const u8, NativeAccessFunc>(compiled.buf.ptr(entry)) };
+        code.insert(compiled, &redirects)?;
+        func.func = exec;
+        func.addr.native = true;
+        func.addr.address = func.func as *const ();
+        for site in &funcs[index].callers {
+            code.patch(site.func, site.offset, exec as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Reverts `funcs[index]` to the interpreter and retires its code.
+    ///
+    /// Native callers are patched to call the stub again. Frames still running in the retired
+    /// code are left alone; see [`Context::deoptimize`].
+    pub fn invalidate(
+        funcs: &mut [Func],
+        code: &mut CodeCache,
+        index: usize,
+    ) -> anyhow::Result<()> {
+        let redirects = Func::unlink(funcs, index);
+        code.remove(index, &redirects)?;
+        let func = &mut funcs[index];
+        func.func = func.stub;
+        func.addr.native = false;
+        func.addr.address = func.code.as_ptr() as *const ();
+        let stub = func.stub as usize;
+        for site in &funcs[index].callers {
+            code.patch(site.func, site.offset, stub)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the call sites in the current code of `funcs[index]`.
+    ///
+    /// Returns the offset and callee stub of each of them, so the code can be redirected to
+    /// the stubs once it is retired.
+    fn unlink(funcs: &mut [Func], index: usize) -> Vec<(usize, usize)> {
+        let mut redirects = Vec::with_capacity(0);
+        for func in funcs.iter_mut() {
+            let stub = func.stub as usize;
+            func.callers.retain(|site| {
+                if site.func == index {
+                    redirects.push((site.offset, stub));
+                }
+                site.func != index
+            });
+        }
+        redirects
+    }
+
     #[cfg(target_arch = "x86_64")]
-    pub fn compile(funcs: &mut [Func], index: usize) -> anyhow::Result<()> {
+    pub fn compile(
+        funcs: &mut [Func],
+        code: &mut CodeCache,
+        options: CompileOptions,
+        index: usize,
+    ) -> anyhow::Result<()> {
         let func = &funcs[index];
+        let mut body = ir::Function::new(&func.code, funcs.len())?;
+        if !options.trace {
+            opt::inline(&mut body, index, funcs);
+            if let Some(feedback) = options.feedback {
+                opt::speculate(&mut body, index, funcs, feedback);
+            }
+            opt::optimize(&mut body);
+        }
         let mut ops = Assembler::<dynasmrt::x64::X64Relocation>::new().unwrap();
         let start = ops.offset();
-        let mut labels = HashMap::with_capacity(0);
-        for (i, insn) in func.code.iter().enumerate() {
-            let opc = *insn & 0xf000;
-            match opc {
-                SMALLOP => {
-                    let op = insn & 0xf00;
-                    match op {
-                        NOOP | MOVE | MEMLOAD | MEMSTORE | RETURN | ADD | SUB | MUL | IMUL
-                        | DIV | IDIV | REM | IREM | PRINT | HALT => {}
-                        _ => {
-                            return Err(anyhow!("Invalid small instruction: 0x{insn:04x}"));
-                        }
-                    }
-                }
-                LOAD | ILOAD => {}
-                JUMP => {
-                    let offset = sign_extend::<12>(insn & 0xfff);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
-                }
-                JUMPZ => {
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
-                }
-                JUMPNZ => {
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
-                }
-                CALL => {
-                    let target = i + 1;
-                    if target >= func.code.len() {
-                        return Err(anyhow!("Invalid call: 0x{insn:04x}"));
-                    }
-                }
-                _ => {
-                    return Err(anyhow!("Invalid instruction: 0x{insn:04x}"));
-                }
-            }
+        let labels = body
+            .blocks
+            .iter()
+            .map(|_| ops.new_dynamic_label())
+            .collect::<Vec<_>>();
+        let regs = RegAlloc::scan(&body);
+        let mut calls = Vec::with_capacity(0);
+        let mut returns = Vec::with_capacity(0);
+        let mut exits = Vec::with_capacity(0);
+        let mut lines = Vec::with_capacity(body.insns.len());
+        if let Some(counter) = options.counter {
+            asm!(ops
+                ; mov t0, QWORD counter.as_ptr() as i64
+                ; inc QWORD [t0]
+            );
         }
-        for (i, insn) in func.code.iter().enumerate() {
-            if let Some(target) = labels.get(&i) {
-                ops.dynamic_label(*target);
+        regs.reload(&mut ops);
+        for (block_index, block) in body.blocks.iter().enumerate() {
+            if !block.reachable {
+                continue;
             }
-            let opc = *insn & 0xf000;
-            match opc {
-                SMALLOP => {
-                    let op = insn & 0xf00;
-                    match op {
-                        NOOP => {}
-                        MOVE => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; mov [BYTE ctx + dst], t0
-                            );
-                        }
-                        MEMLOAD => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + 96]
-                                ; mov t1, [BYTE ctx + src]
-                                ; and t1, 0xffff
-                                ; add t0, t1
-                                ; mov t0, [t0]
-                                ; mov [BYTE ctx + dst], t0
-                            );
-                        }
-                        MEMSTORE => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + 96]
-                                ; mov t1, [BYTE ctx + dst]
-                                ; and t1, 0xffff
-                                ; add t0, t1
-                                ; mov t1, [BYTE ctx + src]
-                                ; mov [t0], t1
-                            );
-                        }
-                        RETURN => {
-                            asm!(ops
-                                ; ret
-                            );
-                        }
-                        ADD => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; add [BYTE ctx + dst], t0
-                            );
-                        }
-                        SUB => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; sub [BYTE ctx + dst], t0
-                            );
-                        }
-                        MUL => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
+            ops.dynamic_label(labels[block_index]);
+            if options.trace {
+                regs.spill(&mut ops);
+                asm!(ops
+                    ; mov t0, QWORD bytecode(funcs, body.frames(index, block.start)[0]) as i64
+                    ; mov t1, QWORD trace as *const () as i64
+                    ; call t1
+                );
+                regs.reload(&mut ops);
+            }
+            for (insn, at) in body.insns[block.start..block.end].iter().zip(block.start..) {
+                lines.push((ops.offset().0, at));
+                match *insn {
+                    Insn::Nop => {}
+                    Insn::Move { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; mov Rq(dst), Rq(src)
+                        );
+                    }
+                    Insn::Const { dst, value } => {
+                        let dst = RegAlloc::host(dst);
+                        if let Ok(value) = i32::try_from(value) {
                             asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; mov t3, [BYTE ctx + dst]
-                                ; mul t3
-                                ; mov [BYTE ctx + dst], t0
+                                ; mov Rq(dst), value
                             );
-                        }
-                        IMUL => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
+                        } else {
                             asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; mov t3, [BYTE ctx + dst]
-                                ; imul t3
-                                ; mov [BYTE ctx + dst], t0
+                                ; mov Rq(dst), QWORD value
                             );
                         }
-                        DIV => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
+                    }
+                    Insn::Binary { op, dst, src } if op.is_checked() => {
+                        // Overflows exit so that the interpreter raises the trap.
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        checked(&mut ops, op, dst, src, exit);
+                    }
+                    Insn::Binary { op, dst, src } => binary(&mut ops, op, dst, src),
+                    Insn::Unary { op, dst, src } => unary(&mut ops, op, dst, src),
+                    Insn::DivRem {
+                        signed,
+                        dst,
+                        rem,
+                        src,
+                    } => {
+                        let dst = RegAlloc::host(dst);
+                        let rem = RegAlloc::host(rem);
+                        let src = RegAlloc::host(src);
+                        // Traps exit so that the interpreter raises them.
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        asm!(ops
+                            ; test Rq(src), Rq(src)
+                            ; jz =>exit
+                        );
+                        if signed {
+                            // `i64::MIN / -1` is the only case where both terms are zero.
                             asm!(ops
-                                ; mov t0, [BYTE ctx + dst]
-                                ; mov t1, [BYTE ctx + src]
-                                ; xor t3, t3
-                                ; div t1
-                                ; mov [BYTE ctx + dst], t0
+                                ; mov t1, QWORD i64::MIN
+                                ; xor t1, Rq(dst)
+                                ; lea t0, [Rq(src) + 1]
+                                ; or t0, t1
+                                ; jz =>exit
                             );
                         }
-                        IDIV => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
+                        asm!(ops
+                            ; mov t0, Rq(dst)
+                        );
+                        if signed {
                             asm!(ops
-                                ; mov t0, [BYTE ctx + dst]
-                                ; mov t1, [BYTE ctx + src]
                                 ; cqo
-                                ; idiv t1
-                                ; mov [BYTE ctx + dst], t0
+                                ; idiv Rq(src)
                             );
-                        }
-                        REM => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
+                        } else {
                             asm!(ops
-                                ; mov t0, [BYTE ctx + dst]
-                                ; mov t1, [BYTE ctx + src]
                                 ; xor t3, t3
-                                ; div t1
-                                ; mov [BYTE ctx + dst], t3
-                            );
-                        }
-                        IREM => {
-                            let dst = ((insn & 0x7) * 8) as i8;
-                            let src = (((insn & 0x38) >> 3) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + dst]
-                                ; mov t1, [BYTE ctx + src]
-                                ; cqo
-                                ; idiv t1
-                                ; mov [BYTE ctx + dst], t3
-                            );
-                        }
-                        PRINT => {
-                            let src = ((insn & 0x7) * 8) as i8;
-                            asm!(ops
-                                ; mov t0, [BYTE ctx + src]
-                                ; mov t1, QWORD print as usize as i64
-                                ; call t1
-                            );
-                        }
-                        HALT => {
-                            asm!(ops
-                                ; mov QWORD [BYTE runner + 96], 0
-                                ; mov t0, QWORD halt as usize as i64
-                                ; jmp t0
+                                ; div Rq(src)
                             );
                         }
-                        _ => {
-                            return Err(anyhow!("Invalid small instruction: 0x{insn:04x}"));
-                        }
+                        asm!(ops
+                            ; mov Rq(dst), t0
+                            ; mov Rq(rem), t3
+                        );
+                    }
+                    Insn::MemLoad { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; mov t0, [BYTE ctx + 96]
+                            ; mov t1, Rq(src)
+                            ; and t1, 0xffff
+                            ; add t0, t1
+                            ; mov Rq(dst), [t0]
+                        );
+                    }
+                    Insn::MemStore { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; mov t0, [BYTE ctx + 96]
+                            ; mov t1, Rq(dst)
+                            ; and t1, 0xffff
+                            ; add t0, t1
+                            ; mov [t0], Rq(src)
+                        );
+                    }
+                    Insn::Print { src, float } => {
+                        let src = RegAlloc::host(src);
+                        let stub = if float {
+                            printf as *const ()
+                        } else {
+                            print as _
+                        };
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; mov t0, Rq(src)
+                            ; mov t1, QWORD stub as i64
+                            ; call t1
+                        );
+                        regs.reload(&mut ops);
+                    }
+                    Insn::Halt => {
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; mov QWORD [BYTE runner + 96], 0
+                            ; mov t0, QWORD halt as *const () as i64
+                            ; jmp t0
+                        );
+                    }
+                    Insn::Return => {
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; ret
+                        );
+                    }
+                    Insn::Jump { target } => {
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; jmp =>label
+                        );
+                    }
+                    Insn::JumpZ { cond, target } => {
+                        let cond = RegAlloc::host(cond);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; test Rq(cond), Rq(cond)
+                            ; jz =>label
+                        );
+                    }
+                    Insn::JumpNz { cond, target } => {
+                        let cond = RegAlloc::host(cond);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; test Rq(cond), Rq(cond)
+                            ; jnz =>label
+                        );
+                    }
+                    Insn::Branch { cond, a, b, target } => {
+                        let a = RegAlloc::host(a);
+                        let b = RegAlloc::host(b);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; cmp Rq(a), Rq(b)
+                        );
+                        match cond {
+                            Cond::Eq => asm!(ops ; je =>label),
+                            Cond::Ne => asm!(ops ; jne =>label),
+                            Cond::Lt => asm!(ops ; jl =>label),
+                            Cond::Ge => asm!(ops ; jge =>label),
+                            Cond::LtU => asm!(ops ; jb =>label),
+                            Cond::GeU => asm!(ops ; jae =>label),
+                        }
+                    }
+                    Insn::Switch { reg, len } => {
+                        let reg = RegAlloc::host(reg);
+                        let default = labels[body.block_of(at + len + 1)];
+                        let table = ops.new_dynamic_label();
+                        asm!(ops
+                            ; cmp Rq(reg), len as i32
+                            ; jae =>default
+                            ; lea t1, [=>table]
+                            ; lea t1, [t1 + Rq(reg) * 4]
+                            ; movsxd t0, DWORD [t1]
+                            ; add t0, t1
+                            ; jmp t0
+                        );
+                        ops.dynamic_label(table);
+                        for case in &body.insns[at + 1..=at + len] {
+                            let label = labels[body.block_of(case.target().unwrap())];
+                            table_entry(&mut ops, label);
+                        }
+                    }
+                    Insn::Case { .. } => {}
+                    Insn::Call { func } => {
+                        // The interpreter would have pushed the frames of inlined callers.
+                        let depth = body.frames(index, at).len() as i32;
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        asm!(ops
+                            ; mov t0, QWORD options.callstack_limit as i64
+                            ; lea t1, [rsp - depth * 8]
+                            ; cmp t1, t0
+                            ; jbe =>exit
+                        );
+                        let addr = funcs[func].func;
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; mov t0, QWORD addr as *const () as i64
+                        );
+                        calls.push((func, ops.offset().0 - 8));
+                        asm!(ops
+                            ; call t0
+                        );
+                        returns.push((ops.offset().0, at));
+                        regs.reload(&mut ops);
+                    }
+                    Insn::Guard { reg, check, then } => {
+                        let reg = RegAlloc::host(reg);
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        match check {
+                            Check::Eq(value) => {
+                                if let Ok(value) = i32::try_from(value) {
+                                    asm!(ops
+                                        ; cmp Rq(reg), value
+                                    );
+                                } else {
+                                    asm!(ops
+                                        ; mov t2, QWORD value
+                                        ; cmp Rq(reg), t2
+                                    );
+                                }
+                                asm!(ops
+                                    ; jne =>exit
+                                );
+                            }
+                        }
+                        if let Some(then) = then {
+                            binary(&mut ops, then.op, then.dst, Operand::Imm(then.value));
+                        }
                     }
                 }
-                LOAD => {
-                    let dst = ((insn & 0x7) * 8) as i8;
-                    let value = ((insn & 0xff8) >> 3) as i32;
-                    asm!(ops
-                        ; mov QWORD [BYTE ctx + dst], value
-                    );
-                }
-                ILOAD => {
-                    let dst = ((insn & 0x7) * 8) as i8;
-                    let value = sign_extend::<9>((insn & 0xff8) >> 3) as i32;
-                    asm!(ops
-                        ; mov QWORD [BYTE ctx + dst], value
-                    );
-                }
-                JUMP => {
-                    let offset = sign_extend::<12>(insn & 0xfff);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; jmp =>label
-                    );
-                }
-                JUMPZ => {
-                    let cond = ((insn & 0x7) * 8) as i8;
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; mov t0, [BYTE ctx + cond]
-                        ; test t0, t0
-                        ; jz =>label
-                    );
-                }
-                JUMPNZ => {
-                    let cond = ((insn & 0x7) * 8) as i8;
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; mov t0, [BYTE ctx + cond]
-                        ; test t0, t0
-                        ; jnz =>label
-                    );
-                }
-                CALL => {
-                    let call_index = insn & 0xfff;
-                    let Some(callee) = funcs.get(call_index as usize) else {
-                        return Err(anyhow!("Invalid function: 0x{insn:04x}"));
-                    };
-                    let addr = callee.func;
-                    asm!(ops
-                        ; mov t0, QWORD addr as usize as i64
-                        ; call t0
-                    );
-                }
-                _ => return Err(anyhow!("Invalid instruction: 0x{insn:04x}")),
             }
         }
-        let func = &mut funcs[index];
-        let buf = ops.finalize().unwrap();
-        let exec = unsafe { mem::transmute(buf.ptr(start)) };
-        func.buf = buf;
-        func.func = exec;
-        func.addr.native = true;
-        func.addr.address = func.func as *const ();
-        Ok(())
+        // Failed guards enter the interpreter at the guarded instruction, like a call through
+        // the stub that returns to the current frame's caller.
+        for (exit, at) in exits {
+            ops.dynamic_label(exit);
+            regs.spill(&mut ops);
+            let frames = body.frames(index, at);
+            asm!(ops
+                ; push 0
+            );
+            for frame in frames[1..].iter().rev() {
+                asm!(ops
+                    ; mov t0, QWORD bytecode(funcs, *frame) as i64
+                    ; push t0
+                );
+            }
+            asm!(ops
+                ; mov t0, QWORD bytecode(funcs, frames[0]) as i64
+                ; mov [BYTE ctx + 0x40], t0 // virtual address
+                ; mov t0, QWORD enter_virtual as *const () as i64
+                ; jmp t0
+            );
+        }
+        let compiled = Code {
+            func: index,
+            buf: ops.finalize().unwrap(),
+            returns: returns
+                .into_iter()
+                .map(|(offset, at)| (offset, body.resume_frames(index, at)))
+                .collect(),
+            lines: lines
+                .into_iter()
+                .map(|(offset, at)| (offset, body.frames(index, at)[0]))
+                .collect(),
+            debug: None,
+            counter: options.counter.cloned(),
+        };
+        Func::install(funcs, code, compiled, start, calls)
     }
 
     #[cfg(target_arch = "aarch64")]
-    pub fn compile(funcs: &mut [Func], index: usize) -> anyhow::Result<()> {
-        use std::collections::hash_map::Entry;
+    pub fn compile(
+        funcs: &mut [Func],
+        code: &mut CodeCache,
+        options: CompileOptions,
+        index: usize,
+    ) -> anyhow::Result<()> {
+        use std::collections::{hash_map::Entry, HashMap};
 
         let func = &funcs[index];
+        let mut body = ir::Function::new(&func.code, funcs.len())?;
+        if !options.trace {
+            opt::inline(&mut body, index, funcs);
+            if let Some(feedback) = options.feedback {
+                opt::speculate(&mut body, index, funcs, feedback);
+            }
+            opt::optimize(&mut body);
+        }
         let mut ops = Assembler::<dynasmrt::aarch64::Aarch64Relocation>::new().unwrap();
-        let mut labels = HashMap::with_capacity(0);
         #[derive(Default)]
         struct Uses {
             branching: bool,
             print: bool,
+            printf: bool,
             halt: bool,
+            guard: bool,
         }
-        let mut uses = Uses::default();
+        let mut uses = Uses {
+            branching: options.trace,
+            ..Uses::default()
+        };
         let mut relocations = HashMap::with_capacity(0);
-        for (i, insn) in func.code.iter().enumerate() {
-            let opc = *insn & 0xf000;
-            match opc {
-                SMALLOP => {
-                    let op = insn & 0xf00;
-                    match op {
-                        NOOP | MOVE | MEMLOAD | MEMSTORE | RETURN | ADD | SUB | MUL | IMUL
-                        | DIV | IDIV | REM | IREM => {}
-                        PRINT => {
-                            uses.branching = true;
-                            uses.print = true;
-                        }
-                        HALT => {
-                            uses.halt = true;
-                        }
-                        _ => {
-                            return Err(anyhow!("Invalid small instruction: 0x{insn:04x}"));
-                        }
+        let mut callees = HashMap::with_capacity(0);
+        let mut calls = Vec::with_capacity(0);
+        let mut returns = Vec::with_capacity(0);
+        for insn in &body.insns {
+            match *insn {
+                Insn::Print { float, .. } => {
+                    uses.branching = true;
+                    if float {
+                        uses.printf = true;
+                    } else {
+                        uses.print = true;
                     }
                 }
-                LOAD | ILOAD => {}
-                JUMP => {
-                    let offset = sign_extend::<12>(insn & 0xfff);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
+                Insn::Halt => {
+                    uses.halt = true;
                 }
-                JUMPZ => {
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
+                Insn::Guard { .. } => {
+                    // Guard exits expect the return address on the callstack.
+                    uses.branching = true;
+                    uses.guard = true;
                 }
-                JUMPNZ => {
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = i as isize + offset as isize;
-                    if target < 0 || target >= func.code.len() as isize {
-                        return Err(anyhow!("Invalid jump: 0x{insn:04x}"));
-                    }
-                    if labels.contains_key(&(target as usize)) {
-                        continue;
-                    }
-                    labels.insert(target as usize, ops.new_dynamic_label());
+                Insn::Binary { op, .. } if op.is_checked() => {
+                    // Overflows deoptimize like failed guards.
+                    uses.branching = true;
+                    uses.guard = true;
                 }
-                CALL => {
+                Insn::DivRem { .. } => {
+                    // So do divisions by zero.
                     uses.branching = true;
-                    let call_index = insn & 0xfff;
-                    let Some(callee) = funcs.get(call_index as usize) else {
-                        return Err(anyhow!("Invalid call: 0x{insn:04x}"));
-                    };
-                    let address = &callee.func as *const _ as usize;
-                    if let Entry::Vacant(e) = relocations.entry(address) {
+                    uses.guard = true;
+                }
+                Insn::Call { func } => {
+                    // Calls deoptimize when the callstack is full, like failed guards.
+                    uses.branching = true;
+                    uses.guard = true;
+                    if let Entry::Vacant(e) = callees.entry(func) {
                         let label = ops.new_dynamic_label();
                         ops.dynamic_label(label);
                         e.insert(label);
+                        calls.push((func, ops.offset().0));
                         asm!(ops
-                            ; .qword address as i64
+                            ; .qword funcs[func].func as *const () as i64
                         );
                     }
-                    let target = i + 1;
-                    if target >= func.code.len() {
-                        return Err(anyhow!("Invalid call: 0x{insn:04x}"));
-                    }
-                }
-                _ => {
-                    return Err(anyhow!("Invalid instruction: 0x{insn:04x}"));
                 }
+                _ => {}
             }
         }
+        if options.trace {
+            let label = ops.new_dynamic_label();
+            ops.dynamic_label(label);
+            relocations.insert(trace as *const () as usize, label);
+            asm!(ops
+                ; .qword trace as *const () as i64
+            );
+        }
         if uses.print {
             let label = ops.new_dynamic_label();
             ops.dynamic_label(label);
-            relocations.insert(print as usize, label);
+            relocations.insert(print as *const () as usize, label);
             asm!(ops
-                ; .qword print as usize as i64
+                ; .qword print as *const () as i64
+            );
+        }
+        if uses.printf {
+            let label = ops.new_dynamic_label();
+            ops.dynamic_label(label);
+            relocations.insert(printf as *const () as usize, label);
+            asm!(ops
+                ; .qword printf as *const () as i64
             );
         }
         if uses.halt {
             let label = ops.new_dynamic_label();
             ops.dynamic_label(label);
-            relocations.insert(halt as usize, label);
+            relocations.insert(halt as *const () as usize, label);
+            asm!(ops
+                ; .qword halt as *const () as i64
+            );
+        }
+        if uses.guard {
+            let label = ops.new_dynamic_label();
+            ops.dynamic_label(label);
+            relocations.insert(enter_virtual as *const () as usize, label);
             asm!(ops
-                ; .qword halt as usize as i64
+                ; .qword enter_virtual as *const () as i64
             );
         }
+        let labels = body
+            .blocks
+            .iter()
+            .map(|_| ops.new_dynamic_label())
+            .collect::<Vec<_>>();
+        let regs = RegAlloc::scan(&body);
+        let mut exits = Vec::with_capacity(0);
+        let mut lines = Vec::with_capacity(body.insns.len());
         let start = ops.offset();
         if uses.branching {
             asm!(ops
                 ; str lr, [x21, -0x8]! // push lr
             );
         }
-        for (i, insn) in func.code.iter().enumerate() {
-            if let Some(target) = labels.get(&i) {
-                ops.dynamic_label(*target);
+        if let Some(counter) = options.counter {
+            load_imm(&mut ops, 0, counter.as_ptr() as i64);
+            asm!(ops
+                ; ldr t1, [t0]
+                ; add t1, t1, 1
+                ; str t1, [t0]
+            );
+        }
+        regs.reload(&mut ops);
+        for (block_index, block) in body.blocks.iter().enumerate() {
+            if !block.reachable {
+                continue;
             }
-            let opc = *insn & 0xf000;
-            match opc {
-                SMALLOP => {
-                    let op = insn & 0xf00;
-                    match op {
-                        NOOP => {}
-                        MOVE => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, src]
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        MEMLOAD => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, 0x60]
-                                ; ldr t1, [x19, src]
-                                ; and t1, t1, 0xffff
-                                ; add t0, t0, t1
-                                ; ldr t0, [t0]
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        MEMSTORE => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, 0x60]
-                                ; ldr t1, [x19, dst]
-                                ; and t1, t1, 0xffff
-                                ; add t0, t0, t1
-                                ; ldr t1, [x19, src]
-                                ; str t1, [t0]
-                            );
-                        }
-                        RETURN => {
-                            if uses.branching {
-                                asm!(ops
-                                    ; ldr lr, [x21], 0x8
-                                );
-                            }
-                            asm!(ops
-                                ; ret
-                            );
-                        }
-                        ADD => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; add t0, t0, t1
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        SUB => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; sub t0, t0, t1
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        MUL | IMUL => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; mul t0, t0, t1
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        // IMUL => {
-                        //     let dst = ((insn & 0x7) * 8) as u32;
-                        //     let src = (((insn & 0x38) >> 3) * 8) as u32;
-                        //     asm!(ops
-                        //         ; ldr t0, [x19, dst]
-                        //         ; ldr t1, [x19, src]
-                        //         ; mul t0, t0, t1
-                        //         ; str t0, [x19, dst]
-                        //     );
-                        // }
-                        DIV => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
-                            asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; udiv t0, t0, t1
-                                ; str t0, [x19, dst]
-                            );
-                        }
-                        IDIV => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
+            ops.dynamic_label(labels[block_index]);
+            if options.trace {
+                let address = relocations[&(trace as *const () as usize)];
+                regs.spill(&mut ops);
+                load_imm(
+                    &mut ops,
+                    0,
+                    bytecode(funcs, body.frames(index, block.start)[0]) as i64,
+                );
+                asm!(ops
+                    ; adr t1, =>address
+                    ; ldr t1, [t1]
+                    ; blr t1
+                );
+                regs.reload(&mut ops);
+            }
+            for (insn, at) in body.insns[block.start..block.end].iter().zip(block.start..) {
+                lines.push((ops.offset().0, at));
+                match *insn {
+                    Insn::Nop => {}
+                    Insn::Move { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; mov X(dst), X(src)
+                        );
+                    }
+                    Insn::Const { dst, value } => load_imm(&mut ops, RegAlloc::host(dst), value),
+                    Insn::Binary { op, dst, src } if op.is_checked() => {
+                        // Overflows exit so that the interpreter raises the trap.
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        checked(&mut ops, op, dst, src, exit);
+                    }
+                    Insn::Binary { op, dst, src } => binary(&mut ops, op, dst, src),
+                    Insn::Unary { op, dst, src } => unary(&mut ops, op, dst, src),
+                    Insn::DivRem {
+                        signed,
+                        dst,
+                        rem,
+                        src,
+                    } => {
+                        let dst = RegAlloc::host(dst);
+                        let rem = RegAlloc::host(rem);
+                        let src = RegAlloc::host(src);
+                        // Traps exit so that the interpreter raises them.
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        asm!(ops
+                            ; cbz X(src), =>exit
+                        );
+                        if signed {
+                            // `i64::MIN / -1` is the only case where both terms are zero.
+                            load_imm(&mut ops, 1, i64::MIN);
                             asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; sdiv t0, t0, t1
-                                ; str t0, [x19, dst]
+                                ; eor t1, t1, X(dst)
+                                ; add t0, XSP(src), 1
+                                ; orr t0, t0, t1
+                                ; cbz t0, =>exit
                             );
                         }
-                        REM => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
+                        if signed {
                             asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; udiv t2, t0, t1
-                                ; msub t2, t2, t1, t0
-                                ; str t2, [x19, dst]
+                                ; sdiv t0, X(dst), X(src)
                             );
-                        }
-                        IREM => {
-                            let dst = ((insn & 0x7) * 8) as u32;
-                            let src = (((insn & 0x38) >> 3) * 8) as u32;
+                        } else {
                             asm!(ops
-                                ; ldr t0, [x19, dst]
-                                ; ldr t1, [x19, src]
-                                ; sdiv t2, t0, t1
-                                ; msub t2, t2, t1, t0
-                                ; str t2, [x19, dst]
+                                ; udiv t0, X(dst), X(src)
                             );
                         }
-                        PRINT => {
-                            let src = ((insn & 0x7) * 8) as u32;
-                            let address = print as usize;
-                            let address = relocations[&address];
+                        // The remainder is written last, so it wins if `rem` is `dst`.
+                        asm!(ops
+                            ; msub t1, t0, X(src), X(dst)
+                            ; mov X(dst), t0
+                            ; mov X(rem), t1
+                        );
+                    }
+                    Insn::MemLoad { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; ldr t0, [x19, 0x60]
+                            ; and t1, X(src), 0xffff
+                            ; add t0, t0, t1
+                            ; ldr X(dst), [t0]
+                        );
+                    }
+                    Insn::MemStore { dst, src } => {
+                        let dst = RegAlloc::host(dst);
+                        let src = RegAlloc::host(src);
+                        asm!(ops
+                            ; ldr t0, [x19, 0x60]
+                            ; and t1, X(dst), 0xffff
+                            ; add t0, t0, t1
+                            ; str X(src), [t0]
+                        );
+                    }
+                    Insn::Print { src, float } => {
+                        let src = RegAlloc::host(src);
+                        let address = if float {
+                            printf as *const ()
+                        } else {
+                            print as _
+                        };
+                        let address = address as usize;
+                        let address = relocations[&address];
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; mov t0, X(src)
+                            ; adr t1, =>address
+                            ; ldr t1, [t1]
+                            ; blr t1
+                        );
+                        regs.reload(&mut ops);
+                    }
+                    Insn::Halt => {
+                        let address = halt as *const () as usize;
+                        let address = relocations[&address];
+                        regs.spill(&mut ops);
+                        asm!(ops
+                            ; adr t0, =>address
+                            ; ldr t0, [t0]
+                            ; blr t0
+                        );
+                    }
+                    Insn::Return => {
+                        regs.spill(&mut ops);
+                        if uses.branching {
                             asm!(ops
-                                ; ldr t0, [x19, src]
-                                ; adr t1, =>address
-                                ; ldr t1, [t1]
-                                ; blr t1
+                                ; ldr lr, [x21], 0x8
                             );
                         }
-                        HALT => {
-                            let address = halt as usize;
-                            let address = relocations[&address];
-                            asm!(ops
-                                ; adr t0, =>address
-                                ; ldr t0, [t0]
-                                ; blr t0
-                            );
+                        asm!(ops
+                            ; ret
+                        );
+                    }
+                    Insn::Jump { target } => {
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; b =>label
+                        );
+                    }
+                    Insn::JumpZ { cond, target } => {
+                        let cond = RegAlloc::host(cond);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; cbz X(cond), =>label
+                        );
+                    }
+                    Insn::JumpNz { cond, target } => {
+                        let cond = RegAlloc::host(cond);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; cbnz X(cond), =>label
+                        );
+                    }
+                    Insn::Branch { cond, a, b, target } => {
+                        let a = RegAlloc::host(a);
+                        let b = RegAlloc::host(b);
+                        let label = labels[body.block_of(target)];
+                        asm!(ops
+                            ; cmp X(a), X(b)
+                        );
+                        match cond {
+                            Cond::Eq => asm!(ops ; b.eq =>label),
+                            Cond::Ne => asm!(ops ; b.ne =>label),
+                            Cond::Lt => asm!(ops ; b.lt =>label),
+                            Cond::Ge => asm!(ops ; b.ge =>label),
+                            Cond::LtU => asm!(ops ; b.lo =>label),
+                            Cond::GeU => asm!(ops ; b.hs =>label),
                         }
-                        _ => {
-                            return Err(anyhow!("Invalid small instruction: 0x{insn:04x}"));
+                    }
+                    Insn::Switch { reg, len } => {
+                        let reg = RegAlloc::host(reg);
+                        let default = labels[body.block_of(at + len + 1)];
+                        let table = ops.new_dynamic_label();
+                        asm!(ops
+                            ; cmp XSP(reg), len as u32
+                            ; b.hs =>default
+                            ; adr t1, =>table
+                            ; add t1, t1, X(reg), lsl 2
+                            ; ldrsw t0, [t1]
+                            ; add t0, t0, t1
+                            ; br t0
+                        );
+                        ops.dynamic_label(table);
+                        for case in &body.insns[at + 1..=at + len] {
+                            let label = labels[body.block_of(case.target().unwrap())];
+                            table_entry(&mut ops, label);
                         }
                     }
-                }
-                LOAD => {
-                    let dst = ((insn & 0x7) * 8) as u32;
-                    let value = ((insn & 0xff8) >> 3) as u64;
-                    asm!(ops
-                        ; mov t0, value
-                        ; str t0, [x19, dst]
-                    );
-                }
-                ILOAD => {
-                    let dst = ((insn & 0x7) * 8) as u32;
-                    let value = sign_extend::<9>((insn & 0xff8) >> 3);
-                    if value >= 0 {
+                    Insn::Case { .. } => {}
+                    Insn::Call { func } => {
+                        // The interpreter would have pushed the frames of inlined callers.
+                        let depth = body.frames(index, at).len() as u32;
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        load_imm(&mut ops, 0, options.callstack_limit as i64);
                         asm!(ops
-                            ; mov t0, value as u64
-                            ; str t0, [x19, dst]
+                            ; sub t1, x21, depth * 8
+                            ; cmp t1, t0
+                            ; b.ls =>exit
                         );
-                    } else {
-                        let value = (-value - 1) as u32;
+                        let address = callees[&func];
+                        regs.spill(&mut ops);
                         asm!(ops
-                            ; movn t0, value
-                            ; str t0, [x19, dst]
+                            ; adr t0, =>address
+                            ; ldr t0, [t0]
+                            ; blr t0
                         );
+                        returns.push((ops.offset().0, at));
+                        regs.reload(&mut ops);
+                    }
+                    Insn::Guard { reg, check, then } => {
+                        let reg = RegAlloc::host(reg);
+                        let exit = ops.new_dynamic_label();
+                        exits.push((exit, at));
+                        match check {
+                            Check::Eq(value) => {
+                                load_imm(&mut ops, 2, value);
+                                asm!(ops
+                                    ; cmp X(reg), t2
+                                    ; b.ne =>exit
+                                );
+                            }
+                        }
+                        if let Some(then) = then {
+                            binary(&mut ops, then.op, then.dst, Operand::Imm(then.value));
+                        }
                     }
                 }
-                JUMP => {
-                    let offset = sign_extend::<12>(insn & 0xfff);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; b =>label
-                    );
-                }
-                JUMPZ => {
-                    let cond = ((insn & 0x7) * 8) as u32;
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; ldr t0, [x19, cond]
-                        ; cbz t0, =>label
-                    );
-                }
-                JUMPNZ => {
-                    let cond = ((insn & 0x7) * 8) as u32;
-                    let offset = sign_extend::<9>((insn & 0xff8) >> 3);
-                    let target = (i as isize + offset as isize) as usize;
-                    let label = labels[&target];
-                    asm!(ops
-                        ; ldr t0, [x19, cond]
-                        ; cbnz t0, =>label
-                    );
-                }
-                CALL => {
-                    let call_index = insn & 0xfff;
-                    let Some(callee) = funcs.get(call_index as usize) else {
-                        return Err(anyhow!("Invalid function: 0x{insn:04x}"));
-                    };
-                    let address = &callee.func as *const _ as usize;
-                    let address = relocations[&address];
-                    asm!(ops
-                        ; adr t0, =>address
-                        ; ldr t0, [t0]
-                        ; blr t0
-                    );
-                }
-                _ => return Err(anyhow!("Invalid instruction: 0x{insn:04x}")),
             }
         }
-        let func = &mut funcs[index];
-        let buf = ops.finalize().unwrap();
-        let exec = unsafe { mem::transmute(buf.ptr(start)) };
-        func.buf = buf;
-        func.func = exec;
-        func.addr.native = true;
-        func.addr.address = func.func as *const ();
-        Ok(())
+        // Failed guards enter the interpreter at the guarded instruction, like a call through
+        // the stub that returns to the current frame's caller.
+        for (exit, at) in exits {
+            ops.dynamic_label(exit);
+            regs.spill(&mut ops);
+            let frames = body.frames(index, at);
+            asm!(ops
+                ; str xzr, [x21, -0x8]! // push 0
+            );
+            for frame in frames[1..].iter().rev() {
+                load_imm(&mut ops, 0, bytecode(funcs, *frame) as i64);
+                asm!(ops
+                    ; str t0, [x21, -0x8]!
+                );
+            }
+            let address = relocations[&(enter_virtual as *const () as usize)];
+            load_imm(&mut ops, 0, bytecode(funcs, frames[0]) as i64);
+            asm!(ops
+                ; str t0, [x19, 0x40] // virtual address
+                ; adr t0, =>address
+                ; ldr t0, [t0]
+                ; br t0
+            );
+        }
+        let compiled = Code {
+            func: index,
+            buf: ops.finalize().unwrap(),
+            returns: returns
+                .into_iter()
+                .map(|(offset, at)| (offset, body.resume_frames(index, at)))
+                .collect(),
+            lines: lines
+                .into_iter()
+                .map(|(offset, at)| (offset, body.frames(index, at)[0]))
+                .collect(),
+            debug: None,
+            counter: options.counter.cloned(),
+        };
+        Func::install(funcs, code, compiled, start, calls)
     }
 }
 
@@ -1028,6 +1919,615 @@ pub struct Address {
     pub address: *const (),
 }
 
+/// Static register allocation for compiled functions.
+///
+/// Every guest register `rN` lives in a fixed host register (see `ABI.md`) for the whole
+/// function. `Context::regs` is only synchronized where other code can observe it: values
+/// are spilled before calls, prints, halts and returns and reloaded on entry and after calls
+/// and prints.
+#[derive(Clone, Copy, Default)]
+struct RegAlloc {
+    /// Guest registers the function reads or writes.
+    used: u8,
+    /// Guest registers the function writes.
+    written: u8,
+}
+
+impl RegAlloc {
+    #[cfg(target_arch = "x86_64")]
+    const HOST: [u8; 8] = [8, 9, 10, 11, 12, 13, 14, 15];
+    #[cfg(target_arch = "aarch64")]
+    const HOST: [u32; 8] = [9, 10, 11, 12, 13, 14, 15, 16];
+
+    fn scan(body: &ir::Function) -> Self {
+        let mut res = Self::default();
+        for block in body.blocks.iter().filter(|block| block.reachable) {
+            for insn in &body.insns[block.start..block.end] {
+                res.used |= insn.reads() | insn.writes();
+                res.written |= insn.writes();
+            }
+        }
+        res
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn host(reg: u8) -> u8 {
+        Self::HOST[reg as usize]
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn host(reg: u8) -> u32 {
+        Self::HOST[reg as usize]
+    }
+
+    /// Writes the modified guest registers back to `Context::regs`.
+    #[cfg(target_arch = "x86_64")]
+    fn spill(&self, ops: &mut Assembler<dynasmrt::x64::X64Relocation>) {
+        for reg in 0..8 {
+            if self.written & (1 << reg) != 0 {
+                let host = Self::host(reg);
+                let offset = (reg * 8) as i8;
+                asm!(ops
+                    ; mov [BYTE ctx + offset], Rq(host)
+                );
+            }
+        }
+    }
+
+    /// Loads the used guest registers from `Context::regs`.
+    #[cfg(target_arch = "x86_64")]
+    fn reload(&self, ops: &mut Assembler<dynasmrt::x64::X64Relocation>) {
+        for reg in 0..8 {
+            if self.used & (1 << reg) != 0 {
+                let host = Self::host(reg);
+                let offset = (reg * 8) as i8;
+                asm!(ops
+                    ; mov Rq(host), [BYTE ctx + offset]
+                );
+            }
+        }
+    }
+
+    /// Writes the modified guest registers back to `Context::regs`.
+    #[cfg(target_arch = "aarch64")]
+    fn spill(&self, ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>) {
+        for reg in 0..8 {
+            if self.written & (1 << reg) != 0 {
+                let host = Self::host(reg);
+                let offset = (reg * 8) as u32;
+                asm!(ops
+                    ; str X(host), [x19, offset]
+                );
+            }
+        }
+    }
+
+    /// Loads the used guest registers from `Context::regs`.
+    #[cfg(target_arch = "aarch64")]
+    fn reload(&self, ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>) {
+        for reg in 0..8 {
+            if self.used & (1 << reg) != 0 {
+                let host = Self::host(reg);
+                let offset = (reg * 8) as u32;
+                asm!(ops
+                    ; ldr X(host), [x19, offset]
+                );
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn binary(ops: &mut Assembler<dynasmrt::x64::X64Relocation>, op: BinOp, dst: u8, src: Operand) {
+    let dst = RegAlloc::host(dst);
+    let src = match src {
+        Operand::Reg(src) => RegAlloc::host(src),
+        Operand::Imm(value) => {
+            match (op, i32::try_from(value)) {
+                (BinOp::Shl, _) => asm!(ops
+                    ; shl Rq(dst), value as i8
+                ),
+                (BinOp::Shr, _) => asm!(ops
+                    ; shr Rq(dst), value as i8
+                ),
+                (BinOp::Add, Ok(value)) => asm!(ops
+                    ; add Rq(dst), value
+                ),
+                (BinOp::Sub, Ok(value)) => asm!(ops
+                    ; sub Rq(dst), value
+                ),
+                (BinOp::And, Ok(value)) => asm!(ops
+                    ; and Rq(dst), value
+                ),
+                _ => {
+                    asm!(ops
+                        ; mov t2, QWORD value
+                    );
+                    return binary_reg(ops, op, dst, 1);
+                }
+            }
+            return;
+        }
+    };
+    binary_reg(ops, op, dst, src)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn binary_reg(ops: &mut Assembler<dynasmrt::x64::X64Relocation>, op: BinOp, dst: u8, src: u8) {
+    match op {
+        BinOp::Add => asm!(ops
+            ; add Rq(dst), Rq(src)
+        ),
+        BinOp::Sub => asm!(ops
+            ; sub Rq(dst), Rq(src)
+        ),
+        BinOp::Mul => asm!(ops
+            ; imul Rq(dst), Rq(src)
+        ),
+        BinOp::Div => asm!(ops
+            ; mov t0, Rq(dst)
+            ; xor t3, t3
+            ; div Rq(src)
+            ; mov Rq(dst), t0
+        ),
+        BinOp::IDiv => asm!(ops
+            ; mov t0, Rq(dst)
+            ; cqo
+            ; idiv Rq(src)
+            ; mov Rq(dst), t0
+        ),
+        BinOp::Rem => asm!(ops
+            ; mov t0, Rq(dst)
+            ; xor t3, t3
+            ; div Rq(src)
+            ; mov Rq(dst), t3
+        ),
+        BinOp::IRem => asm!(ops
+            ; mov t0, Rq(dst)
+            ; cqo
+            ; idiv Rq(src)
+            ; mov Rq(dst), t3
+        ),
+        BinOp::Shl => asm!(ops
+            ; mov t2, Rq(src)
+            ; shl Rq(dst), cl
+        ),
+        BinOp::Shr => asm!(ops
+            ; mov t2, Rq(src)
+            ; shr Rq(dst), cl
+        ),
+        BinOp::And => asm!(ops
+            ; and Rq(dst), Rq(src)
+        ),
+        BinOp::FAdd => {
+            asm!(ops
+                ; movq xmm0, Rq(dst)
+                ; movq xmm1, Rq(src)
+                ; addsd xmm0, xmm1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FSub => {
+            asm!(ops
+                ; movq xmm0, Rq(dst)
+                ; movq xmm1, Rq(src)
+                ; subsd xmm0, xmm1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FMul => {
+            asm!(ops
+                ; movq xmm0, Rq(dst)
+                ; movq xmm1, Rq(src)
+                ; mulsd xmm0, xmm1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FDiv => {
+            asm!(ops
+                ; movq xmm0, Rq(dst)
+                ; movq xmm1, Rq(src)
+                ; divsd xmm0, xmm1
+            );
+            store_float(ops, dst);
+        }
+        // Above sets 1, below -1 and parity marks unordered operands, which also set below.
+        BinOp::FCmp => asm!(ops
+            ; movq xmm0, Rq(dst)
+            ; movq xmm1, Rq(src)
+            ; xor t0, t0
+            ; xor t2, t2
+            ; xor t3, t3
+            ; ucomisd xmm0, xmm1
+            ; seta al
+            ; setb cl
+            ; setp dl
+            ; sub t0, t2
+            ; mov t2, 2
+            ; test t3, t3
+            ; cmovnz t0, t2
+            ; mov Rq(dst), t0
+        ),
+        BinOp::MulH => asm!(ops
+            ; mov t0, Rq(dst)
+            ; imul Rq(src)
+            ; mov Rq(dst), t3
+        ),
+        BinOp::MulHU => asm!(ops
+            ; mov t0, Rq(dst)
+            ; mul Rq(src)
+            ; mov Rq(dst), t3
+        ),
+        BinOp::CheckedAdd
+        | BinOp::CheckedIAdd
+        | BinOp::CheckedSub
+        | BinOp::CheckedISub
+        | BinOp::CheckedMul
+        | BinOp::CheckedIMul => unreachable!("checked operations are lowered by `checked`"),
+    }
+}
+
+/// Lowers a checked operation, which jumps to `overflow` with `dst` unchanged if the result
+/// does not fit.
+#[cfg(target_arch = "x86_64")]
+fn checked(
+    ops: &mut Assembler<dynasmrt::x64::X64Relocation>,
+    op: BinOp,
+    dst: u8,
+    src: Operand,
+    overflow: DynamicLabel,
+) {
+    let dst = RegAlloc::host(dst);
+    let src = match src {
+        Operand::Reg(src) => RegAlloc::host(src),
+        Operand::Imm(value) => {
+            asm!(ops
+                ; mov t2, QWORD value
+            );
+            1
+        }
+    };
+    asm!(ops
+        ; mov t0, Rq(dst)
+    );
+    match op {
+        BinOp::CheckedAdd => asm!(ops
+            ; add t0, Rq(src)
+            ; jc =>overflow
+        ),
+        BinOp::CheckedIAdd => asm!(ops
+            ; add t0, Rq(src)
+            ; jo =>overflow
+        ),
+        BinOp::CheckedSub => asm!(ops
+            ; sub t0, Rq(src)
+            ; jc =>overflow
+        ),
+        BinOp::CheckedISub => asm!(ops
+            ; sub t0, Rq(src)
+            ; jo =>overflow
+        ),
+        // Sets the carry if the high half in `t3` is nonzero.
+        BinOp::CheckedMul => asm!(ops
+            ; mul Rq(src)
+            ; jc =>overflow
+        ),
+        BinOp::CheckedIMul => asm!(ops
+            ; imul t0, Rq(src)
+            ; jo =>overflow
+        ),
+        _ => unreachable!("not a checked operation: {op:?}"),
+    }
+    asm!(ops
+        ; mov Rq(dst), t0
+    );
+}
+
+/// Moves the result of float arithmetic from `xmm0` to `dst`, replacing NaN with the canonical
+/// NaN like `canonical_nan`.
+#[cfg(target_arch = "x86_64")]
+fn store_float(ops: &mut Assembler<dynasmrt::x64::X64Relocation>, dst: u8) {
+    asm!(ops
+        ; movq Rq(dst), xmm0
+        ; mov t0, QWORD f64::NAN.to_bits() as i64
+        ; ucomisd xmm0, xmm0
+        ; cmovp Rq(dst), t0
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+fn unary(ops: &mut Assembler<dynasmrt::x64::X64Relocation>, op: UnOp, dst: u8, src: u8) {
+    let dst = RegAlloc::host(dst);
+    let src = RegAlloc::host(src);
+    match op {
+        UnOp::IntToFloat => asm!(ops
+            ; cvtsi2sd xmm0, Rq(src)
+            ; movq Rq(dst), xmm0
+        ),
+        UnOp::FloatToInt => {
+            // `cvttsd2si` returns `i64::MIN` for NaN and out of range values, which has to be
+            // fixed up to saturate like `as i64`.
+            let done = ops.new_dynamic_label();
+            let nan = ops.new_dynamic_label();
+            asm!(ops
+                ; movq xmm0, Rq(src)
+                ; cvttsd2si Rq(dst), xmm0
+                ; mov t0, QWORD i64::MIN
+                ; cmp Rq(dst), t0
+                ; jne =>done
+                ; ucomisd xmm0, xmm0
+                ; jp =>nan
+                ; xorpd xmm1, xmm1
+                ; ucomisd xmm0, xmm1
+                ; jb =>done
+                ; mov Rq(dst), QWORD i64::MAX
+                ; jmp =>done
+                ; =>nan
+                ; xor Rq(dst), Rq(dst)
+                ; =>done
+            );
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn load_imm(ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>, reg: u32, value: i64) {
+    if value < 0 && !value < 0x10000 {
+        let value = !value as u32;
+        asm!(ops
+            ; movn X(reg), value
+        );
+        return;
+    }
+    let value = value as u64;
+    let chunk = |shift: u32| ((value >> shift) & 0xffff) as u32;
+    asm!(ops
+        ; movz X(reg), chunk(0)
+    );
+    if chunk(16) != 0 {
+        asm!(ops
+            ; movk X(reg), chunk(16), lsl 16
+        );
+    }
+    if chunk(32) != 0 {
+        asm!(ops
+            ; movk X(reg), chunk(32), lsl 32
+        );
+    }
+    if chunk(48) != 0 {
+        asm!(ops
+            ; movk X(reg), chunk(48), lsl 48
+        );
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn binary(
+    ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>,
+    op: BinOp,
+    dst: u8,
+    src: Operand,
+) {
+    let dst = RegAlloc::host(dst);
+    let src = match src {
+        Operand::Reg(src) => RegAlloc::host(src),
+        Operand::Imm(value) => {
+            match op {
+                BinOp::Shl => asm!(ops
+                    ; lsl X(dst), X(dst), value as u32
+                ),
+                BinOp::Shr => asm!(ops
+                    ; lsr X(dst), X(dst), value as u32
+                ),
+                BinOp::Add if (0..0x1000).contains(&value) => asm!(ops
+                    ; add XSP(dst), XSP(dst), value as u32
+                ),
+                BinOp::Sub if (0..0x1000).contains(&value) => asm!(ops
+                    ; sub XSP(dst), XSP(dst), value as u32
+                ),
+                _ => {
+                    load_imm(ops, 2, value);
+                    return binary_reg(ops, op, dst, 2);
+                }
+            }
+            return;
+        }
+    };
+    binary_reg(ops, op, dst, src)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn binary_reg(
+    ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>,
+    op: BinOp,
+    dst: u32,
+    src: u32,
+) {
+    match op {
+        BinOp::Add => asm!(ops
+            ; add X(dst), X(dst), X(src)
+        ),
+        BinOp::Sub => asm!(ops
+            ; sub X(dst), X(dst), X(src)
+        ),
+        BinOp::Mul => asm!(ops
+            ; mul X(dst), X(dst), X(src)
+        ),
+        BinOp::Div => asm!(ops
+            ; udiv X(dst), X(dst), X(src)
+        ),
+        BinOp::IDiv => asm!(ops
+            ; sdiv X(dst), X(dst), X(src)
+        ),
+        BinOp::Rem => asm!(ops
+            ; udiv t3, X(dst), X(src)
+            ; msub X(dst), t3, X(src), X(dst)
+        ),
+        BinOp::IRem => asm!(ops
+            ; sdiv t3, X(dst), X(src)
+            ; msub X(dst), t3, X(src), X(dst)
+        ),
+        BinOp::Shl => asm!(ops
+            ; lsl X(dst), X(dst), X(src)
+        ),
+        BinOp::Shr => asm!(ops
+            ; lsr X(dst), X(dst), X(src)
+        ),
+        BinOp::And => asm!(ops
+            ; and X(dst), X(dst), X(src)
+        ),
+        BinOp::FAdd => {
+            asm!(ops
+                ; fmov d0, X(dst)
+                ; fmov d1, X(src)
+                ; fadd d0, d0, d1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FSub => {
+            asm!(ops
+                ; fmov d0, X(dst)
+                ; fmov d1, X(src)
+                ; fsub d0, d0, d1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FMul => {
+            asm!(ops
+                ; fmov d0, X(dst)
+                ; fmov d1, X(src)
+                ; fmul d0, d0, d1
+            );
+            store_float(ops, dst);
+        }
+        BinOp::FDiv => {
+            asm!(ops
+                ; fmov d0, X(dst)
+                ; fmov d1, X(src)
+                ; fdiv d0, d0, d1
+            );
+            store_float(ops, dst);
+        }
+        // Unordered operands set V and clear N, so they end up at 0 before being replaced by 2.
+        BinOp::FCmp => asm!(ops
+            ; fmov d0, X(dst)
+            ; fmov d1, X(src)
+            ; fcmp d0, d1
+            ; cset X(dst), gt
+            ; csinv X(dst), X(dst), xzr, pl
+            ; mov t0, 2
+            ; csel X(dst), t0, X(dst), vs
+        ),
+        BinOp::MulH => asm!(ops
+            ; smulh X(dst), X(dst), X(src)
+        ),
+        BinOp::MulHU => asm!(ops
+            ; umulh X(dst), X(dst), X(src)
+        ),
+        BinOp::CheckedAdd
+        | BinOp::CheckedIAdd
+        | BinOp::CheckedSub
+        | BinOp::CheckedISub
+        | BinOp::CheckedMul
+        | BinOp::CheckedIMul => unreachable!("checked operations are lowered by `checked`"),
+    }
+}
+
+/// Lowers a checked operation, which branches to `overflow` with `dst` unchanged if the result
+/// does not fit.
+#[cfg(target_arch = "aarch64")]
+fn checked(
+    ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>,
+    op: BinOp,
+    dst: u8,
+    src: Operand,
+    overflow: DynamicLabel,
+) {
+    let dst = RegAlloc::host(dst);
+    let src = match src {
+        Operand::Reg(src) => RegAlloc::host(src),
+        Operand::Imm(value) => {
+            load_imm(ops, 2, value);
+            2
+        }
+    };
+    match op {
+        BinOp::CheckedAdd => asm!(ops
+            ; adds t0, X(dst), X(src)
+            ; b.cs =>overflow
+        ),
+        BinOp::CheckedIAdd => asm!(ops
+            ; adds t0, X(dst), X(src)
+            ; b.vs =>overflow
+        ),
+        // Clears the carry on borrow.
+        BinOp::CheckedSub => asm!(ops
+            ; subs t0, X(dst), X(src)
+            ; b.cc =>overflow
+        ),
+        BinOp::CheckedISub => asm!(ops
+            ; subs t0, X(dst), X(src)
+            ; b.vs =>overflow
+        ),
+        BinOp::CheckedMul => asm!(ops
+            ; umulh t1, X(dst), X(src)
+            ; cbnz t1, =>overflow
+            ; mul t0, X(dst), X(src)
+        ),
+        // The high half has to be the sign extension of the low half.
+        BinOp::CheckedIMul => asm!(ops
+            ; mul t0, X(dst), X(src)
+            ; smulh t1, X(dst), X(src)
+            ; cmp t1, t0, asr 63
+            ; b.ne =>overflow
+        ),
+        _ => unreachable!("not a checked operation: {op:?}"),
+    }
+    asm!(ops
+        ; mov X(dst), t0
+    );
+}
+
+/// Moves the result of float arithmetic from `d0` to `dst`, replacing NaN with the canonical
+/// NaN like `canonical_nan`.
+#[cfg(target_arch = "aarch64")]
+fn store_float(ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>, dst: u32) {
+    load_imm(ops, 0, f64::NAN.to_bits() as i64);
+    asm!(ops
+        ; fmov X(dst), d0
+        ; fcmp d0, d0
+        ; csel X(dst), t0, X(dst), vs
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+fn unary(ops: &mut Assembler<dynasmrt::aarch64::Aarch64Relocation>, op: UnOp, dst: u8, src: u8) {
+    let dst = RegAlloc::host(dst);
+    let src = RegAlloc::host(src);
+    match op {
+        UnOp::IntToFloat => asm!(ops
+            ; scvtf d0, X(src)
+            ; fmov X(dst), d0
+        ),
+        // Saturates and turns NaN into 0 like `as i64`.
+        UnOp::FloatToInt => asm!(ops
+            ; fmov d0, X(src)
+            ; fcvtzs X(dst), d0
+        ),
+    }
+}
+
+/// Emits a jump table entry holding the offset of `label` from the entry.
+fn table_entry<R: Relocation>(ops: &mut Assembler<R>, label: DynamicLabel) {
+    ops.push_i32(0);
+    ops.dynamic_relocation(label, 0, 4, 4, R::from_size(RelocationSize::DWord));
+}
+
+/// Address of the bytecode instruction at `frame`.
+fn bytecode(funcs: &[Func], frame: Frame) -> *const u16 {
+    funcs[frame.func].code[frame.pc..].as_ptr()
+}
+
 #[inline(always)]
 pub const fn sign_extend<const BITS: usize>(value: u16) -> i64 {
     if ((value >> (BITS - 1)) & 1) != 0 {
@@ -1037,8 +2537,44 @@ pub const fn sign_extend<const BITS: usize>(value: u16) -> i64 {
     }
 }
 
-pub extern "C" fn print_num(num: i64) {
-    println!("{num}");
+/// Returns the offset of an `LJUMP` whose following word is `low`.
+pub const fn long_offset(insn: u16, low: u16) -> i64 {
+    sign_extend::<12>(insn & 0xfff) << 16 | low as i64
+}
+
+/// Replaces a NaN result of float arithmetic with the canonical quiet NaN `0x7ff8000000000000`,
+/// so results do not depend on how the host propagates NaN operands.
+pub fn canonical_nan(value: f64) -> f64 {
+    if value.is_nan() {
+        f64::NAN
+    } else {
+        value
+    }
+}
+
+/// The result of `FCMP`: -1, 0 or 1 if `a` is less than, equal to or greater than `b`, and 2 if
+/// they are unordered because either is NaN.
+pub fn compare_floats(a: f64, b: f64) -> i64 {
+    match a.partial_cmp(&b) {
+        Some(ordering) => ordering as i64,
+        None => 2,
+    }
+}
+
+pub extern "C" fn print_num(num: i64, ctx: &mut Context) {
+    ctx.print(num);
+}
+
+/// Called by compiled code for `PRINTF`, with the register's bits like `print_num`.
+pub extern "C" fn print_float(bits: u64, ctx: &mut Context) {
+    ctx.print_float(f64::from_bits(bits));
+}
+
+/// Called by compiled code at the start of every block while tracing.
+pub extern "C" fn trace_block(ctx: &mut Context, pc: *const u16) {
+    if let Some(tracer) = &mut ctx.tracer {
+        tracer.block(pc, &ctx.regs, &ctx.funcs);
+    }
 }
 
 #[cfg(all(target_arch = "x86_64", target_family = "unix"))]
@@ -1066,7 +2602,7 @@ fn generate_stub(addr: *const ()) -> (ExecutableBuffer, NativeAccessFunc) {
         ; ret
     );
     let buf = ops.finalize().unwrap();
-    let stub = unsafe { mem::transmute(buf.ptr(offset)) };
+    let stub = unsafe { mem::transmute::<*const u8, NativeAccessFunc>(buf.ptr(offset)) };
     (buf, stub)
 }
 
@@ -1098,7 +2634,7 @@ fn generate_stub(addr: *const ()) -> (ExecutableBuffer, NativeAccessFunc) {
         ; ret
     );
     let buf = ops.finalize().unwrap();
-    let stub = unsafe { mem::transmute(buf.ptr(offset)) };
+    let stub = unsafe { mem::transmute::<*const u8, NativeAccessFunc>(buf.ptr(offset)) };
     (buf, stub)
 }
 
@@ -1112,6 +2648,7 @@ fn generate_stub(addr: *const ()) -> (ExecutableBuffer, NativeAccessFunc) {
     let offset = ops.offset();
     asm!(ops // (x20: *Runner, x19: *Context) custom
         // Save mapped registers
+        ; str lr, [x21, -0x8]! // push lr
         ; str xzr, [x21, -0x8]! // push 0
         ; adr t0, ->addr
         ; ldr t0, [t0]
@@ -1135,6 +2672,6 @@ fn generate_stub(addr: *const ()) -> (ExecutableBuffer, NativeAccessFunc) {
         ; ret
     );
     let buf = ops.finalize().unwrap();
-    let stub = unsafe { mem::transmute(buf.ptr(offset)) };
+    let stub = unsafe { mem::transmute::<*const u8, NativeAccessFunc>(buf.ptr(offset)) };
     (buf, stub)
 }
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    ir,
+    opcodes::mnemonic,
+    runtime::{Func, Value},
+};
+
+/// Writes a line-oriented execution trace, enabled by setting `Context::tracer`.
+///
+/// Whenever execution enters a basic block, a line with the bytecode location and all registers
+/// is written:
+///
+/// ```text
+/// func1+3: block r0=0 r1=10 r2=1 r3=0 r4=0 r5=0 r6=0 r7=0
+/// ```
+///
+/// The interpreter writes these lines itself, while compiled code calls out to the tracer at
+/// the start of every block. Functions are compiled without optimizations while tracing, so
+/// both tiers see the same blocks and their traces can be diffed. With `insns`, the interpreter
+/// additionally writes each instruction it executes followed by the registers it changed:
+///
+/// ```text
+/// func1+4: 0611 SUB r1=9
+/// ```
+pub struct Tracer {
+    output: Box<dyn Write>,
+    insns: bool,
+    /// Registers as of the last line written.
+    regs: [i64; 8],
+    /// The last instruction line, completed with register changes once the next line starts.
+    pending: Option<String>,
+    /// Offsets at which blocks start in each function, sorted.
+    blocks: HashMap<usize, Vec<usize>>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, insns: bool) -> Self {
+        Self {
+            output,
+            insns,
+            regs: [0; 8],
+            pending: None,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Traces into the file at `path`, replacing it.
+    pub fn create(path: impl AsRef<Path>, insns: bool) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::new(Box::new(file), insns))
+    }
+
+    /// Records that the interpreter is about to execute `insn` at `pc`.
+    pub fn step(&mut self, pc: *const u16, insn: u16, regs: &[Value; 8], funcs: &[Func]) {
+        let Some(frame) = Func::locate(funcs, pc) else {
+            return;
+        };
+        let starts = self.blocks.entry(frame.func).or_insert_with(|| {
+            ir::Function::new(&funcs[frame.func].code, funcs.len())
+                .map(|body| body.blocks.iter().map(|block| block.start).collect())
+                .unwrap_or_default()
+        });
+        if starts.binary_search(&frame.pc).is_ok() {
+            self.block(pc, regs, funcs);
+        }
+        if self.insns {
+            self.complete(regs);
+            self.pending = Some(format!(
+                "{}+{}: {insn:04x} {}",
+                Func::symbol(funcs, frame.func),
+                frame.pc,
+                mnemonic(insn)
+            ));
+        }
+    }
+
+    /// Records that execution enters the block at `pc`.
+    pub fn block(&mut self, pc: *const u16, regs: &[Value; 8], funcs: &[Func]) {
+        let Some(frame) = Func::locate(funcs, pc) else {
+            return;
+        };
+        self.complete(regs);
+        let mut line = format!("{}+{}: block", Func::symbol(funcs, frame.func), frame.pc);
+        for (index, reg) in regs.iter().enumerate() {
+            line += &format!(" r{index}={}", unsafe { reg.int });
+        }
+        self.write(&line);
+    }
+
+    /// Writes the pending instruction line with the registers changed since, and flushes the
+    /// output.
+    pub fn flush(&mut self, regs: &[Value; 8]) {
+        self.complete(regs);
+        if let Err(err) = self.output.flush() {
+            eprintln!("Could not write trace: {err}");
+        }
+    }
+
+    fn complete(&mut self, regs: &[Value; 8]) {
+        let mut line = self.pending.take();
+        for (index, (old, new)) in self.regs.iter_mut().zip(regs).enumerate() {
+            let new = unsafe { new.int };
+            if *old != new {
+                if let Some(line) = &mut line {
+                    *line += &format!(" r{index}={new}");
+                }
+                *old = new;
+            }
+        }
+        if let Some(line) = line {
+            self.write(&line);
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if let Err(err) = writeln!(self.output, "{line}") {
+            eprintln!("Could not write trace: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use super::Tracer;
+    use crate::{
+        opcodes::{__add, __call, __jumpnz, __load, __return, __sub},
+        runtime::{Context, Func, Runner},
+    };
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(compile: bool, insns: bool) -> String {
+        let mut ctx = Context::default();
+        let mut runner = Runner::default();
+        ctx.funcs
+            .push(Func::named("main", vec![__call(1), __return()]));
+        ctx.funcs.push(Func::named(
+            "sum",
+            vec![
+                __load(0, 0),
+                __load(1, 3),
+                __load(2, 1),
+                __add(0, 1),
+                __sub(1, 2),
+                __jumpnz(1, -2),
+                __return(),
+            ],
+        ));
+        let output = Output::default();
+        ctx.tracer = Some(Tracer::new(Box::new(output.clone()), insns));
+        if compile {
+            ctx.compile(1).unwrap();
+        }
+        ctx.pc = ctx.funcs[0].addr.address as *const u16;
+        runner.run(&mut ctx);
+        String::from_utf8(output.0.take()).unwrap()
+    }
+
+    #[test]
+    fn test_trace_interpreted_and_compiled() {
+        let interpreted = trace(false, false);
+        assert_eq!(
+            interpreted.lines().collect::<Vec<_>>(),
+            [
+                "main+0: block r0=0 r1=0 r2=0 r3=0 r4=0 r5=0 r6=0 r7=0",
+                "sum+0: block r0=0 r1=0 r2=0 r3=0 r4=0 r5=0 r6=0 r7=0",
+                "sum+3: block r0=0 r1=3 r2=1 r3=0 r4=0 r5=0 r6=0 r7=0",
+                "sum+3: block r0=3 r1=2 r2=1 r3=0 r4=0 r5=0 r6=0 r7=0",
+                "sum+3: block r0=5 r1=1 r2=1 r3=0 r4=0 r5=0 r6=0 r7=0",
+                "sum+6: block r0=6 r1=0 r2=1 r3=0 r4=0 r5=0 r6=0 r7=0",
+            ]
+        );
+        assert_eq!(trace(true, false), interpreted);
+        let insns = trace(false, true);
+        assert!(insns.contains("sum+0: 1000 LOAD\n"));
+        assert!(insns.contains("sum+4: 0611 SUB r1=2\n"));
+    }
+}